@@ -1,9 +1,130 @@
 use std::collections::HashMap;
 
+//The result of an authentication attempt
+#[derive(Clone, Default)]
+pub struct AuthResult {
+    pub authenticated: bool,
+    pub username: String,
+    pub uid: String,
+    pub groups: Vec<String>,
+    //The audiences the token was verified for, empty if the token is not bound to audiences
+    pub audiences: Vec<String>,
+    //Additional information about the user, returned as user.extra of the TokenReview
+    pub extra: HashMap<String, Vec<String>>,
+    //Set if the token could not be checked because a backend failed, e.g. the ldap servers are down
+    pub error: Option<String>,
+}
+
+impl AuthResult {
+    //Result for a token that could not be authenticated
+    pub fn denied() -> Self {
+        AuthResult::default()
+    }
+
+    //Result for a token that could not be checked because a backend failed
+    pub fn failed(error: &str) -> Self {
+        AuthResult {
+            error: Some(error.to_string()),
+            ..AuthResult::default()
+        }
+    }
+
+    //Result for an authenticated user, if no uid is known the username is used
+    pub fn granted(username: &str, uid: &str, groups: Vec<String>) -> Self {
+        let uid = if uid.is_empty() { username } else { uid };
+        AuthResult {
+            authenticated: true,
+            username: username.to_string(),
+            uid: uid.to_string(),
+            groups,
+            audiences: vec![],
+            extra: HashMap::new(),
+            error: None,
+        }
+    }
+}
+
+//Get the values of an argument that can have multiple values
+//cmdparser also collects the -x arguments following a --x argument as its values, so the values end at the first one starting with -
+pub fn argument_values(arguments: &HashMap<String, Vec<String>>, name: &str) -> Option<Vec<String>> {
+    arguments.get(name).map(|values| {
+        values
+            .iter()
+            .take_while(|value| !value.starts_with("-"))
+            .cloned()
+            .collect()
+    })
+}
+
+//Replace the ${name} variables of a template with their values, unknown variables are removed
+pub fn replace_vars(template: &str, vars: &HashMap<String, String>) -> String {
+    replace_vars_escaped(template, vars, |_, value| value.to_string())
+}
+
+//Like replace_vars, but the values are escaped for the syntax of the template, e.g. a ldap filter
+//escape gets the name and the value of the variable, so trusted values can be kept as they are
+pub fn replace_vars_escaped(template: &str, vars: &HashMap<String, String>, escape: impl Fn(&str, &str) -> String) -> String {
+    let mut result = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c == '$' {
+            match chars.next() {
+                Some('{') => {
+                    let mut var_name = String::new();
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            if let Some(val) = vars.get(&var_name[..]) {
+                                result.push_str(&escape(&var_name, val));
+                            }
+                            break;
+                        }
+                        var_name.push(c);
+                    }
+                }
+                Some(c) => {
+                    result.push('$');
+                    result.push(c);
+                }
+                None => result.push('$'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
 pub trait Authenticator {
-    async fn auth(&self, token: &str) -> (bool, String, Vec<String>);
+    //audiences are the spec.audiences of the TokenReview, empty if the apiserver didn't send any
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult;
     fn new (arguments: HashMap<String, Vec<String>>) -> Self;
 }
 
 //List of available authenticators as args
-pub const AUTHENTICATORS: &[&str] = &["json_auth", "ldap_auth"];
\ No newline at end of file
+pub const AUTHENTICATORS: &[&str] = &["json_auth", "ldap_auth", "token_file_auth", "htpasswd_auth", "login_token_auth", "jwt_auth", "oidc_auth", "introspection_auth", "webhook_auth", "k8s_sa_auth", "radius_auth", "ssh_sig_auth", "http_auth", "exec_auth", "wasm_auth", "script_auth"];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_are_replaced_and_escaped() {
+        let vars: HashMap<String, String> = HashMap::from([
+            (String::from("username"), String::from("alice")),
+            (String::from("base_dn"), String::from("dc=example,dc=com")),
+        ]);
+
+        assert_eq!(
+            replace_vars("uid=${username},${base_dn} ${unknown} $5 ${open", &vars),
+            "uid=alice,dc=example,dc=com  $5 "
+        );
+        //The escape gets the name of the variable and is only used for the values
+        assert_eq!(
+            replace_vars_escaped("(${username}|${base_dn})", &vars, |name, value| match name {
+                "username" => value.to_uppercase(),
+                _ => value.to_string(),
+            }),
+            "(ALICE|dc=example,dc=com)"
+        );
+    }
+}
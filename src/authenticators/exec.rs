@@ -0,0 +1,287 @@
+use std::{collections::HashMap, path::Path, process::Stdio, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt, io::AsyncWriteExt, process::Command, sync::Semaphore};
+
+use super::authenticator::{AuthResult, Authenticator};
+
+/*
+This is an exec authenticator, it runs a program for every authentication, so one-off integrations can be written in any language.
+The token and the audiences are written as JSON to the stdin of the program, the result is read as JSON from its stdout.
+The token is never passed as argument or environment variable, those can be read by other users with ps or /proc.
+The program gets an empty environment except PATH, so it can't read the secrets of kubeauth either.
+*/
+
+//Default seconds a program may run
+const DEFAULT_TIMEOUT: u64 = 5;
+
+//Default number of programs running at the same time
+const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+//Longer output is cut off and fails to parse
+const MAX_OUTPUT: u64 = 1024 * 1024;
+
+//Written to the stdin of the program
+#[derive(Serialize)]
+struct ExecRequest<'a> {
+    token: &'a str,
+    audiences: &'a [String],
+}
+
+//Read from the stdout of the program, only authenticated is required
+#[derive(Deserialize)]
+struct ExecResponse {
+    #[serde(default)]
+    authenticated: bool,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    uid: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    extra: HashMap<String, Vec<String>>,
+    //The audiences the token is valid for, if the program checks them
+    #[serde(default)]
+    audiences: Vec<String>,
+}
+
+//Auther struct for external programs
+pub struct ExecAuthenticator {
+    command: String,
+    timeout: Duration,
+    //Limits the programs running at the same time, so a flood of tokens can't start a flood of processes
+    slots: Semaphore,
+}
+
+impl Authenticator for ExecAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let request = ExecRequest { token, audiences };
+        let input = match serde_json::to_vec(&request) {
+            Ok(input) => input,
+            Err(_) => return AuthResult::denied(),
+        };
+
+        //Waiting for a free slot counts against the timeout too
+        //A program that can't run, fails or hangs is no denial, the apiserver must not cache it
+        let response = match tokio::time::timeout(self.timeout, self.run(&input)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                println!("exec_auth program {} failed: {}", self.command, e);
+                return AuthResult::failed("exec_auth: program failed");
+            }
+            Err(_) => {
+                //The program is killed when its future is dropped
+                println!("exec_auth program {} timed out after {}s", self.command, self.timeout.as_secs());
+                return AuthResult::failed("exec_auth: program timed out");
+            }
+        };
+
+        if !response.authenticated {
+            return AuthResult::denied();
+        }
+        if response.username.is_empty() {
+            println!("exec_auth program {} returned no username", self.command);
+            return AuthResult::denied();
+        }
+
+        println!("Authenticated user {} with exec program", response.username);
+        let mut result = AuthResult::granted(&response.username, &response.uid, response.groups);
+        result.extra = response.extra;
+        result.audiences = response.audiences;
+        result
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading exec_auth authenticator");
+
+        //Get --exec_command argument, required
+        let command = match arguments.get("exec_command") {
+            Some(command) if !command.is_empty() => command[0].clone(),
+            _ => {
+                println!("--exec_command is required for exec_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        if !Path::new(&command).is_file() {
+            println!("--exec_command {} is not a file", command);
+            std::process::exit(1);
+        }
+
+        //Get --exec_timeout argument in seconds
+        let timeout = match arguments.get("exec_timeout") {
+            Some(timeout) => match timeout[0].parse::<u64>() {
+                Ok(timeout) if timeout > 0 => timeout,
+                _ => {
+                    println!("--exec_timeout is not a valid number of seconds");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_TIMEOUT,
+        };
+
+        //Get --exec_max_concurrent argument
+        let max_concurrent = match arguments.get("exec_max_concurrent") {
+            Some(max_concurrent) => match max_concurrent[0].parse::<usize>() {
+                Ok(max_concurrent) if max_concurrent > 0 => max_concurrent,
+                _ => {
+                    println!("--exec_max_concurrent is not a valid number");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_MAX_CONCURRENT,
+        };
+
+        ExecAuthenticator {
+            command,
+            timeout: Duration::from_secs(timeout),
+            slots: Semaphore::new(max_concurrent),
+        }
+    }
+}
+
+impl ExecAuthenticator {
+    //Run the program with the request on stdin and parse its stdout
+    async fn run(&self, input: &[u8]) -> Result<ExecResponse, String> {
+        let _slot = self.slots.acquire().await.map_err(|e| e.to_string())?;
+
+        let mut child = Command::new(&self.command)
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            //The program can log to the output of kubeauth
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to start: {}", e))?;
+
+        //Closing stdin tells the program the request is complete
+        if let Some(mut stdin) = child.stdin.take() {
+            //A program that exits without reading stdin is fine, its output decides
+            let _ = stdin.write_all(input).await;
+        }
+
+        let mut output: Vec<u8> = vec![];
+        if let Some(stdout) = child.stdout.take() {
+            stdout
+                .take(MAX_OUTPUT)
+                .read_to_end(&mut output)
+                .await
+                .map_err(|e| format!("failed to read output: {}", e))?;
+        }
+
+        let status = child.wait().await.map_err(|e| e.to_string())?;
+        if !status.success() {
+            return Err(format!("exited with {}", status));
+        }
+
+        serde_json::from_slice(&output).map_err(|e| format!("invalid output: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    //Write a /bin/sh script and load it with the given timeout
+    fn authenticator(name: &str, script: &str, timeout: u64) -> ExecAuthenticator {
+        let command = std::env::temp_dir().join(format!("kubeauth-exec-test-{}-{}.sh", std::process::id(), name));
+        std::fs::write(&command, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("exec_command"), vec![command.to_string_lossy().to_string()]);
+        arguments.insert(String::from("exec_timeout"), vec![timeout.to_string()]);
+        ExecAuthenticator::new(arguments)
+    }
+
+    #[rocket::async_test]
+    async fn the_output_is_mapped() {
+        let exec = authenticator(
+            "grant",
+            r#"case "$(cat)" in
+*'"token":"good"'*) echo '{"authenticated": true, "username": "alice", "uid": "1001", "groups": ["dev"], "extra": {"team": ["a"]}, "audiences": ["api"]}' ;;
+*'"token":"nouser"'*) echo '{"authenticated": true}' ;;
+*) echo '{"authenticated": false}' ;;
+esac"#,
+            5,
+        );
+
+        let result = exec.auth("good", &[String::from("api")]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.uid, "1001");
+        assert_eq!(result.groups, vec!["dev"]);
+        assert_eq!(result.extra["team"], vec!["a"]);
+        assert_eq!(result.audiences, vec!["api"]);
+
+        for token in ["bad", "nouser"] {
+            let result = exec.auth(token, &[]).await;
+            assert!(!result.authenticated, "{}", token);
+            assert!(result.error.is_none(), "{}", token);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn the_environment_is_empty() {
+        std::env::set_var("KUBEAUTH_EXEC_TEST_SECRET", "secret");
+        let exec = authenticator(
+            "env",
+            r#"cat > /dev/null
+echo "{\"authenticated\": true, \"username\": \"env\", \"groups\": [$(env | cut -d= -f1 | sed 's/.*/"&"/' | paste -sd, -)]}""#,
+            5,
+        );
+
+        let result = exec.auth("token", &[]).await;
+        assert!(result.authenticated);
+        assert!(result.groups.contains(&String::from("PATH")));
+        assert!(!result.groups.contains(&String::from("KUBEAUTH_EXEC_TEST_SECRET")));
+        //Only PATH and what the shell sets itself
+        assert!(result.groups.iter().all(|name| ["PATH", "PWD", "SHLVL", "_", "OLDPWD"].contains(&name.as_str())), "{:?}", result.groups);
+    }
+
+    #[rocket::async_test]
+    async fn failing_programs_fail_the_review() {
+        for (name, script) in [
+            ("exit", "cat > /dev/null\necho '{\"authenticated\": true, \"username\": \"alice\"}'\nexit 3"),
+            ("malformed", "cat > /dev/null\necho 'authenticated: true'"),
+            ("empty", "cat > /dev/null"),
+        ] {
+            let result = authenticator(name, script, 5).auth("token", &[]).await;
+            assert!(!result.authenticated, "{}", name);
+            assert_eq!(result.error.as_deref(), Some("exec_auth: program failed"), "{}", name);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn hanging_programs_are_killed() {
+        let pid_file = std::env::temp_dir().join(format!("kubeauth-exec-test-{}-pid", std::process::id()));
+        let exec = authenticator("hang", &format!("echo $$ > {}\nexec sleep 30", pid_file.display()), 1);
+
+        let started = std::time::Instant::now();
+        let result = exec.auth("token", &[]).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!result.authenticated);
+        assert_eq!(result.error.as_deref(), Some("exec_auth: program timed out"));
+
+        //The killed program is gone or a zombie until tokio reaps it
+        let pid = std::fs::read_to_string(&pid_file).unwrap().trim().to_string();
+        let mut running = true;
+        for _ in 0..50 {
+            let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+            if !status.contains("State:\tS") && !status.contains("State:\tR") {
+                running = false;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!running, "program {} still runs", pid);
+    }
+}
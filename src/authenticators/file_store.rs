@@ -0,0 +1,77 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+/*
+A file backed store that is reloaded when the file changes on disk.
+Authenticators using a local file (users.json, token files, ...) keep their parsed
+content in a FileStore, so edits to the file are picked up without a restart.
+*/
+
+struct Loaded<T> {
+    modified: Option<SystemTime>,
+    content: Arc<T>,
+}
+
+pub struct FileStore<T> {
+    path: String,
+    parse: fn(&str) -> Result<T, String>,
+    loaded: RwLock<Loaded<T>>,
+}
+
+impl<T> FileStore<T> {
+    //Load the file once, fails if the file can not be read or parsed
+    pub fn load(path: &str, parse: fn(&str) -> Result<T, String>) -> Result<Self, String> {
+        let modified = FileStore::<T>::modified(path);
+        let content = FileStore::<T>::read(path, parse)?;
+
+        Ok(FileStore {
+            path: path.to_string(),
+            parse,
+            loaded: RwLock::new(Loaded {
+                modified,
+                content: Arc::new(content),
+            }),
+        })
+    }
+
+    //Get the current content, reloads the file first if it changed since the last load
+    //If the changed file can not be parsed, the previous content is kept
+    pub fn get(&self) -> Arc<T> {
+        let modified = FileStore::<T>::modified(&self.path);
+
+        {
+            let loaded = self.loaded.read().unwrap();
+            if loaded.modified == modified {
+                return loaded.content.clone();
+            }
+        }
+
+        let mut loaded = self.loaded.write().unwrap();
+        //Another request might have reloaded the file in the meantime
+        if loaded.modified != modified {
+            match FileStore::<T>::read(&self.path, self.parse) {
+                Ok(content) => {
+                    println!("Reloaded {}", self.path);
+                    loaded.content = Arc::new(content);
+                }
+                Err(e) => {
+                    println!("Failed to reload {}, keeping previous content: {}", self.path, e);
+                }
+            }
+            loaded.modified = modified;
+        }
+        loaded.content.clone()
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn read(path: &str, parse: fn(&str) -> Result<T, String>) -> Result<T, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        parse(&content)
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, path::Path};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+/*
+This is a htpasswd authenticator, it uses the same credential files as the apache htpasswd tool.
+Supported hashes are bcrypt ($2y$), apr1-MD5 ($apr1$), SHA1 ({SHA}) and crypt.
+Group membership is read from an optional apache htgroup file.
+*/
+
+//Alphabet used by the md5 crypt variants to encode the hash
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+//Auther struct for using htpasswd and htgroup files
+pub struct HtpasswdAuthenticator {
+    //username -> hash
+    users: FileStore<HashMap<String, String>>,
+    //username -> groups
+    groups: Option<FileStore<HashMap<String, Vec<String>>>>,
+}
+
+impl Authenticator for HtpasswdAuthenticator {
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        //Split token by :, tokens without : are not meant for this authenticator
+        let (username, password) = match token.split_once(":") {
+            Some(split) => split,
+            None => return AuthResult::denied(),
+        };
+
+        println!("Authenticating user {} with htpasswd", username);
+
+        //Get the users, reloads the htpasswd file if it changed
+        let users = self.users.get();
+        let hash = match users.get(username) {
+            Some(hash) => hash,
+            None => return AuthResult::denied(),
+        };
+
+        if !HtpasswdAuthenticator::verify_password(password, hash) {
+            return AuthResult::denied();
+        }
+
+        //Get the groups of the user from the htgroup file
+        let groups = match &self.groups {
+            Some(groups) => groups.get().get(username).cloned().unwrap_or_default(),
+            None => vec![],
+        };
+
+        AuthResult::granted(username, username, groups)
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading htpasswd_auth authenticator");
+
+        //Get --htpasswd_file_path argument, required
+        let htpasswd_file_path = match arguments.get("htpasswd_file_path") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--htpasswd_file_path is required for htpasswd_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        if !Path::new(&htpasswd_file_path).exists() {
+            println!("--htpasswd_file_path is not valid");
+            std::process::exit(1);
+        }
+
+        let users = match FileStore::load(&htpasswd_file_path, HtpasswdAuthenticator::parse_htpasswd) {
+            Ok(users) => users,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        //Get --htgroup_file_path argument, optional
+        let groups = match arguments.get("htgroup_file_path") {
+            Some(path) if !path.is_empty() => {
+                if !Path::new(&path[0]).exists() {
+                    println!("--htgroup_file_path is not valid");
+                    std::process::exit(1);
+                }
+                match FileStore::load(&path[0], HtpasswdAuthenticator::parse_htgroup) {
+                    Ok(groups) => Some(groups),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        HtpasswdAuthenticator { users, groups }
+    }
+}
+
+impl HtpasswdAuthenticator {
+    //Parse a htpasswd file, every line is username:hash
+    fn parse_htpasswd(content: &str) -> Result<HashMap<String, String>, String> {
+        let mut users: HashMap<String, String> = HashMap::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            match line.split_once(":") {
+                Some((username, hash)) if !username.is_empty() && !hash.is_empty() => {
+                    users.insert(username.to_string(), hash.to_string());
+                }
+                _ => return Err(format!("htpasswd file line {}: expected username:hash", index + 1)),
+            }
+        }
+
+        Ok(users)
+    }
+
+    //Parse a htgroup file, every line is group: user1 user2 ...
+    //The result is inverted to username -> groups for the lookup
+    fn parse_htgroup(content: &str) -> Result<HashMap<String, Vec<String>>, String> {
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let (group, members) = match line.split_once(":") {
+                Some((group, members)) if !group.trim().is_empty() => (group.trim(), members),
+                _ => return Err(format!("htgroup file line {}: expected group: user1 user2", index + 1)),
+            };
+
+            for member in members.split_whitespace() {
+                let member_groups = groups.entry(member.to_string()).or_default();
+                if !member_groups.iter().any(|g| g == group) {
+                    member_groups.push(group.to_string());
+                }
+            }
+        }
+
+        Ok(groups)
+    }
+
+    //Check a password against a htpasswd hash
+    fn verify_password(password: &str, hash: &str) -> bool {
+        if let Some(sha) = hash.strip_prefix("{SHA}") {
+            let digest = BASE64.encode(Sha1::digest(password.as_bytes()));
+            return bool::from(digest.as_bytes().ct_eq(sha.as_bytes()));
+        }
+
+        if hash.starts_with("$apr1$") {
+            return match HtpasswdAuthenticator::apr1_crypt(password, hash) {
+                Some(computed) => bool::from(computed.as_bytes().ct_eq(hash.as_bytes())),
+                None => false,
+            };
+        }
+
+        //bcrypt ($2y$, $2a$, $2b$), md5 crypt ($1$) and classic crypt
+        pwhash::unix::verify(password, hash)
+    }
+
+    //apr1 is the md5 crypt algorithm with the magic $apr1$, as used by apache
+    fn apr1_crypt(password: &str, hash: &str) -> Option<String> {
+        let magic = "$apr1$";
+        let salt = hash.strip_prefix(magic)?.split("$").next()?;
+        //Salts only use the crypt alphabet, other entries are malformed and never match
+        if !salt.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'/') {
+            return None;
+        }
+        //Salts are at most 8 characters
+        let salt = &salt[..salt.len().min(8)];
+        let password = password.as_bytes();
+
+        let mut alternate = Md5::new();
+        alternate.update(password);
+        alternate.update(salt.as_bytes());
+        alternate.update(password);
+        let alternate = alternate.finalize();
+
+        let mut context = Md5::new();
+        context.update(password);
+        context.update(magic.as_bytes());
+        context.update(salt.as_bytes());
+
+        let mut remaining = password.len();
+        while remaining > 0 {
+            let len = remaining.min(16);
+            context.update(&alternate[..len]);
+            remaining -= len;
+        }
+
+        let mut i = password.len();
+        while i > 0 {
+            if i & 1 == 1 {
+                context.update([0u8]);
+            } else {
+                context.update(&password[..1]);
+            }
+            i >>= 1;
+        }
+        let mut result = context.finalize();
+
+        //1000 rounds to slow down brute forcing
+        for round in 0..1000 {
+            let mut context = Md5::new();
+            if round & 1 == 1 {
+                context.update(password);
+            } else {
+                context.update(result);
+            }
+            if round % 3 != 0 {
+                context.update(salt.as_bytes());
+            }
+            if round % 7 != 0 {
+                context.update(password);
+            }
+            if round & 1 == 1 {
+                context.update(result);
+            } else {
+                context.update(password);
+            }
+            result = context.finalize();
+        }
+
+        let mut encoded = String::new();
+        for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+            let value = ((result[a] as u32) << 16) | ((result[b] as u32) << 8) | result[c] as u32;
+            HtpasswdAuthenticator::encode_64(&mut encoded, value, 4);
+        }
+        HtpasswdAuthenticator::encode_64(&mut encoded, result[11] as u32, 2);
+
+        Some(format!("{}{}${}", magic, salt, encoded))
+    }
+
+    fn encode_64(out: &mut String, mut value: u32, chars: usize) {
+        for _ in 0..chars {
+            out.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //htpasswd -nbm myName myPassword, from the apache documentation
+    const APR1: &str = "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/";
+    //htpasswd -nbs, SHA1 of password
+    const SHA: &str = "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+    //Test vector of OpenBSD bcrypt for U*U, $2y$ is the same algorithm
+    const BCRYPT: &str = "$2y$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+
+    #[test]
+    fn known_hashes_are_verified() {
+        assert_eq!(HtpasswdAuthenticator::apr1_crypt("myPassword", APR1).as_deref(), Some(APR1));
+        assert!(HtpasswdAuthenticator::verify_password("myPassword", APR1));
+        assert!(HtpasswdAuthenticator::verify_password("password", SHA));
+        assert!(HtpasswdAuthenticator::verify_password("U*U", BCRYPT));
+    }
+
+    #[test]
+    fn wrong_passwords_are_rejected() {
+        for hash in [APR1, SHA, BCRYPT] {
+            assert!(!HtpasswdAuthenticator::verify_password("wrong", hash), "{}", hash);
+            assert!(!HtpasswdAuthenticator::verify_password("", hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn unsupported_and_malformed_hashes_never_match() {
+        for hash in [
+            "$argon2id$v=19$m=65536,t=3,p=4$c2FsdHNhbHQ$aGFzaGhhc2hoYXNo",
+            "{SSHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+            "$apr1$sält$HqJZimcKQFAMYayBlzkrA/",
+            "$apr1$",
+            "myPassword",
+        ] {
+            assert!(!HtpasswdAuthenticator::verify_password("myPassword", hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn htpasswd_and_htgroup_files_are_parsed() {
+        let users = HtpasswdAuthenticator::parse_htpasswd(&format!("# comment\n\nalice:{}\nbob:{}\n", APR1, SHA)).unwrap();
+        assert_eq!(users["alice"], APR1);
+        assert_eq!(users["bob"], SHA);
+        assert_eq!(
+            HtpasswdAuthenticator::parse_htpasswd("alice").err().unwrap(),
+            "htpasswd file line 1: expected username:hash"
+        );
+
+        let groups = HtpasswdAuthenticator::parse_htgroup("dev: alice bob\nops: alice alice\n").unwrap();
+        assert_eq!(groups["alice"], vec!["dev", "ops"]);
+        assert_eq!(groups["bob"], vec!["dev"]);
+    }
+}
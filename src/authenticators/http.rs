@@ -0,0 +1,493 @@
+use std::{collections::HashMap, fs, time::Duration};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::authenticator::{replace_vars, replace_vars_escaped, AuthResult, Authenticator};
+
+/*
+This is a http authenticator, it sends a templated request to a http api and maps the JSON response to a user.
+The url, headers and body of the request can use the variables ${username}, ${password} and ${token}.
+Tokens in the form username:password fill ${username} and ${password}, ${token} is always the whole token.
+The fields of the response are selected with simple JSONPath expressions ($.a.b, $.a[0], $.a[*], $['a-b']).
+*/
+
+//Default timeout of the requests in seconds
+const DEFAULT_TIMEOUT: u64 = 10;
+
+//The http config file
+#[derive(Deserialize)]
+struct HttpConfig {
+    #[serde(default = "default_method")]
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    //JSON body, the variables are replaced in all strings of it, no body is sent if not set
+    body: Option<Value>,
+    //Has to select true for a successful login, without it every 2xx response is a successful login
+    success_path: Option<String>,
+    //Without it the username of the token is used, if it selects nothing the login is denied
+    username_path: Option<String>,
+    uid_path: Option<String>,
+    groups_path: Option<String>,
+    //extra key -> path
+    #[serde(default)]
+    extra_paths: HashMap<String, String>,
+    //Ca file to verify the api with, the system roots are used if not set
+    ca_file: Option<String>,
+    //Client certificate and key in PEM files for mutual tls
+    client_cert_file: Option<String>,
+    client_key_file: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    connect_timeout: Option<u64>,
+}
+
+fn default_method() -> String {
+    String::from("POST")
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+//A step of a JSONPath expression
+#[derive(Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    //Negative indexes count from the end
+    Index(i64),
+    Wildcard,
+}
+
+//A parsed JSONPath expression
+struct JsonPath {
+    segments: Vec<PathSegment>,
+}
+
+impl JsonPath {
+    fn parse(path: &str) -> Result<JsonPath, String> {
+        let mut rest = match path.trim().strip_prefix("$") {
+            Some(rest) => rest,
+            None => return Err(format!("JSONPath {} has to start with $", path)),
+        };
+
+        let mut segments: Vec<PathSegment> = vec![];
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix(".") {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let key = &after[..end];
+                if key.is_empty() {
+                    return Err(format!("JSONPath {} has an empty key", path));
+                }
+                segments.push(if key == "*" { PathSegment::Wildcard } else { PathSegment::Key(key.to_string()) });
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix("[") {
+                let end = match after.find("]") {
+                    Some(end) => end,
+                    None => return Err(format!("JSONPath {} has an unclosed [", path)),
+                };
+                let inner = after[..end].trim();
+                let segment = if inner == "*" {
+                    PathSegment::Wildcard
+                } else if let Some(key) = inner
+                    .strip_prefix("'")
+                    .and_then(|k| k.strip_suffix("'"))
+                    .or(inner.strip_prefix("\"").and_then(|k| k.strip_suffix("\"")))
+                {
+                    PathSegment::Key(key.to_string())
+                } else {
+                    match inner.parse::<i64>() {
+                        Ok(index) => PathSegment::Index(index),
+                        Err(_) => return Err(format!("JSONPath {} has an invalid index [{}]", path, inner)),
+                    }
+                };
+                segments.push(segment);
+                rest = &after[end + 1..];
+            } else {
+                return Err(format!("JSONPath {} is not valid at {}", path, rest));
+            }
+        }
+        Ok(JsonPath { segments })
+    }
+
+    //All values the path selects
+    fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![value];
+        for segment in &self.segments {
+            let mut next: Vec<&Value> = vec![];
+            for value in current {
+                match (segment, value) {
+                    (PathSegment::Key(key), Value::Object(map)) => next.extend(map.get(key)),
+                    (PathSegment::Index(index), Value::Array(array)) => {
+                        let index = if *index < 0 { array.len() as i64 + index } else { *index };
+                        if index >= 0 {
+                            next.extend(array.get(index as usize));
+                        }
+                    }
+                    (PathSegment::Wildcard, Value::Array(array)) => next.extend(array.iter()),
+                    (PathSegment::Wildcard, Value::Object(map)) => next.extend(map.values()),
+                    _ => {}
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    //The selected values as strings, arrays are flattened and nulls skipped
+    fn strings(&self, value: &Value) -> Vec<String> {
+        let mut strings: Vec<String> = vec![];
+        for value in self.select(value) {
+            match value {
+                Value::Array(array) => strings.extend(array.iter().filter_map(value_string)),
+                value => strings.extend(value_string(value)),
+            }
+        }
+        strings
+    }
+
+    //The first selected value as string
+    fn string(&self, value: &Value) -> Option<String> {
+        self.select(value).into_iter().find_map(value_string)
+    }
+}
+
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+//Auther struct for http apis
+pub struct HttpAuthenticator {
+    method: reqwest::Method,
+    url: String,
+    headers: HashMap<String, String>,
+    body: Option<Value>,
+    //The token has to be username:password if the request uses ${username} or ${password}
+    needs_credentials: bool,
+    success_path: Option<JsonPath>,
+    username_path: Option<JsonPath>,
+    uid_path: Option<JsonPath>,
+    groups_path: Option<JsonPath>,
+    extra_paths: Vec<(String, JsonPath)>,
+    client: reqwest::Client,
+}
+
+impl Authenticator for HttpAuthenticator {
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let (username, password) = match token.split_once(":") {
+            Some((username, password)) if !username.is_empty() => (username, password),
+            _ if self.needs_credentials => return AuthResult::denied(),
+            _ => ("", ""),
+        };
+
+        let vars: HashMap<String, String> = HashMap::from([
+            (String::from("username"), username.to_string()),
+            (String::from("password"), password.to_string()),
+            (String::from("token"), token.to_string()),
+        ]);
+
+        //An api that can't be reached or answers with a server error is no denial, the apiserver must not cache it
+        let response = match self.send(&vars).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("http_auth request failed: {}", e);
+                return AuthResult::failed("http_auth: http api unavailable");
+            }
+        };
+        let response = match response {
+            Some(response) => response,
+            None => return AuthResult::denied(),
+        };
+
+        if let Some(success_path) = &self.success_path {
+            if success_path.select(&response).first() != Some(&&Value::Bool(true)) {
+                return AuthResult::denied();
+            }
+        }
+
+        //A username_path that selects nothing denies, the username of the token is only used without username_path
+        let username = match &self.username_path {
+            Some(path) => path.string(&response).unwrap_or_default(),
+            None => username.to_string(),
+        };
+        if username.is_empty() {
+            println!("http_auth response has no username");
+            return AuthResult::denied();
+        }
+
+        let uid = self.uid_path.as_ref().and_then(|path| path.string(&response)).unwrap_or_default();
+        let groups = self.groups_path.as_ref().map(|path| path.strings(&response)).unwrap_or_default();
+
+        println!("Authenticated user {} with http api", username);
+        let mut result = AuthResult::granted(&username, &uid, groups);
+        for (key, path) in &self.extra_paths {
+            let values = path.strings(&response);
+            if !values.is_empty() {
+                result.extra.insert(key.clone(), values);
+            }
+        }
+        result
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading http_auth authenticator");
+
+        //Get --http_config_file argument, required
+        let config_file = match arguments.get("http_config_file") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--http_config_file is required for http_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        let config: HttpConfig = match fs::read_to_string(&config_file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to load --http_config_file {}: {}", config_file, e);
+                std::process::exit(1);
+            }
+        };
+
+        match HttpAuthenticator::from_config(config) {
+            Ok(authenticator) => authenticator,
+            Err(e) => {
+                println!("Invalid --http_config_file {}: {}", config_file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+impl HttpAuthenticator {
+    fn from_config(config: HttpConfig) -> Result<HttpAuthenticator, String> {
+        let method = reqwest::Method::from_bytes(config.method.to_uppercase().as_bytes())
+            .map_err(|_| format!("invalid method {}", config.method))?;
+
+        if !config.url.starts_with("https://") {
+            println!("WARNING: http_auth url {} is not using https, credentials are sent in plaintext", config.url);
+        }
+
+        let templates = [config.url.clone(), config.body.as_ref().map(|b| b.to_string()).unwrap_or_default()]
+            .into_iter()
+            .chain(config.headers.values().cloned())
+            .collect::<Vec<String>>()
+            .join("\n");
+        let needs_credentials = templates.contains("${username}") || templates.contains("${password}");
+
+        let parse = |path: &Option<String>| path.as_deref().map(JsonPath::parse).transpose();
+        let success_path = parse(&config.success_path)?;
+        let username_path = parse(&config.username_path)?;
+        let uid_path = parse(&config.uid_path)?;
+        let groups_path = parse(&config.groups_path)?;
+        let mut extra_paths: Vec<(String, JsonPath)> = vec![];
+        for (key, path) in &config.extra_paths {
+            extra_paths.push((key.clone(), JsonPath::parse(path)?));
+        }
+
+        if username_path.is_none() && !needs_credentials {
+            return Err(String::from("username_path is required if the request doesn't use ${username} or ${password}"));
+        }
+        if success_path.is_none() {
+            println!("WARNING: http_auth has no success_path, every 2xx response is a successful login");
+        }
+
+        let mut client = reqwest::Client::builder().timeout(Duration::from_secs(config.timeout));
+        if let Some(connect_timeout) = config.connect_timeout {
+            client = client.connect_timeout(Duration::from_secs(connect_timeout));
+        }
+        if let Some(ca_file) = &config.ca_file {
+            let pem = fs::read(ca_file).map_err(|e| format!("failed to read ca_file {}: {}", ca_file, e))?;
+            let certificate = reqwest::Certificate::from_pem(&pem).map_err(|e| format!("invalid ca_file {}: {}", ca_file, e))?;
+            client = client.add_root_certificate(certificate);
+        }
+        match (&config.client_cert_file, &config.client_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let mut pem = fs::read(cert_file).map_err(|e| format!("failed to read client_cert_file {}: {}", cert_file, e))?;
+                let key = fs::read(key_file).map_err(|e| format!("failed to read client_key_file {}: {}", key_file, e))?;
+                //reqwest wants the certificate and the key in one PEM
+                pem.push(b'\n');
+                pem.extend_from_slice(&key);
+                let identity = reqwest::Identity::from_pem(&pem).map_err(|e| format!("invalid client certificate: {}", e))?;
+                client = client.identity(identity);
+            }
+            (None, None) => {}
+            _ => return Err(String::from("client_cert_file and client_key_file have to be set together")),
+        }
+        if config.insecure_skip_tls_verify {
+            println!("WARNING: http_auth skips the tls verification of {}", config.url);
+            client = client.danger_accept_invalid_certs(true);
+        }
+        let client = client.build().map_err(|e| format!("failed to create http client: {}", e))?;
+
+        Ok(HttpAuthenticator {
+            method,
+            url: config.url,
+            headers: config.headers,
+            body: config.body,
+            needs_credentials,
+            success_path,
+            username_path,
+            uid_path,
+            groups_path,
+            extra_paths,
+            client,
+        })
+    }
+
+    //Send the request, returns None if the api denied the login
+    async fn send(&self, vars: &HashMap<String, String>) -> Result<Option<Value>, String> {
+        //Values in the url are percent encoded, so they can't change the path or add query parameters
+        let url = replace_vars_escaped(&self.url, vars, |_, value| percent_encode(value));
+
+        let mut request = self.client.request(self.method.clone(), &url);
+        for (name, value) in &self.headers {
+            let value = replace_vars(value, vars);
+            //Line breaks would inject headers
+            if value.contains(['\r', '\n']) {
+                return Ok(None);
+            }
+            request = request.header(name, value);
+        }
+        if let Some(body) = &self.body {
+            request = request.json(&replace_json_vars(body, vars));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("request to {} failed: {}", self.url, e))?;
+
+        if response.status().is_server_error() {
+            return Err(format!("{} returned {}", self.url, response.status()));
+        }
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        response
+            .json::<Value>()
+            .await
+            .map(Some)
+            .map_err(|e| format!("invalid response of {}: {}", self.url, e))
+    }
+}
+
+//Replace the variables in all strings of a JSON value, the values stay valid JSON strings
+fn replace_json_vars(value: &Value, vars: &HashMap<String, String>) -> Value {
+    match value {
+        Value::String(s) => Value::String(replace_vars(s, vars)),
+        Value::Array(array) => Value::Array(array.iter().map(|v| replace_json_vars(v, vars)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), replace_json_vars(v, vars)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+//Percent encode everything except the unreserved characters of RFC 3986
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::authenticators::test_server::TestServer;
+
+    fn authenticator(server: &TestServer, config: Value) -> HttpAuthenticator {
+        authenticator_for(&server.url, config)
+    }
+
+    fn authenticator_for(url: &str, config: Value) -> HttpAuthenticator {
+        let mut config = config;
+        config["url"] = Value::String(format!("{}/login", url));
+        HttpAuthenticator::from_config(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn success_path_has_to_select_true() {
+        let server = TestServer::start(|request| {
+            if request.body.contains("alicepw") {
+                (200, json!({"ok": true, "user": {"login": "alice"}}).to_string())
+            } else if request.body.contains("bob") {
+                (503, String::new())
+            } else if request.body.contains("carol") {
+                (401, String::new())
+            } else {
+                (200, json!({"ok": false}).to_string())
+            }
+        });
+        let config = json!({"body": {"user": "${username}", "password": "${password}"}, "success_path": "$.ok", "username_path": "$.user.login"});
+        let http = authenticator(&server, config.clone());
+
+        let result = http.auth("alice:alicepw", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+
+        let result = http.auth("alice:wrong", &[]).await;
+        assert!(!result.authenticated);
+        assert!(result.error.is_none());
+        let result = http.auth("carol:carolpw", &[]).await;
+        assert!(!result.authenticated);
+        assert!(result.error.is_none());
+
+        //A server error or an api that is down fails instead of denying
+        let result = http.auth("bob:bobpw", &[]).await;
+        assert!(!result.authenticated);
+        assert_eq!(result.error.as_deref(), Some("http_auth: http api unavailable"));
+        let down = authenticator_for(&TestServer::unused_url(), config);
+        let result = down.auth("alice:alicepw", &[]).await;
+        assert!(!result.authenticated);
+        assert_eq!(result.error.as_deref(), Some("http_auth: http api unavailable"));
+    }
+
+    #[rocket::async_test]
+    async fn a_username_path_that_selects_nothing_denies() {
+        let server = TestServer::start(|_| (200, json!({"ok": true}).to_string()));
+        let with_path = authenticator(
+            &server,
+            json!({"body": {"user": "${username}", "password": "${password}"}, "username_path": "$.user.login"}),
+        );
+        assert!(!with_path.auth("alice:alicepw", &[]).await.authenticated);
+
+        //Without username_path the username of the token is used
+        let without_path = authenticator(&server, json!({"body": {"user": "${username}", "password": "${password}"}}));
+        let result = without_path.auth("alice:alicepw", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+
+        let down = authenticator_for(&TestServer::unused_url(), json!({"body": {"user": "${username}", "password": "${password}"}}));
+        let result = down.auth("alice:alicepw", &[]).await;
+        assert!(!result.authenticated);
+        assert!(result.error.is_some());
+    }
+}
@@ -1,15 +1,21 @@
-use std::{collections::HashMap, env, fs::File, io::BufReader, path::Path};
+use std::{collections::HashMap, path::Path};
 
 use argon2::{
     password_hash::{
-        rand_core::OsRng, PasswordHasher, SaltString
+        rand_core::{OsRng, RngCore}, PasswordHash, PasswordHasher, PasswordVerifier, SaltString
     },
     Argon2
 };
+use chrono::{DateTime, Utc};
 
 use serde::{Serialize, Deserialize};
 
-use super::authenticator::Authenticator;
+use super::authenticator::{argument_values, AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+//Prefix of api tokens, api tokens look like kat_<id>_<secret>
+//Neither the id nor the hex secret contain :, so they never look like user:password tokens
+const API_TOKEN_PREFIX: &str = "kat_";
 
 //The user struct
 #[derive(Serialize, Deserialize)]
@@ -17,6 +23,25 @@ struct User {
     username: String,
     password: String,
     groups: Vec<String>,
+    //Api tokens of the user, used for service users instead of the password
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+    //Base32 TOTP secret, the user has to append a code to the password if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp_secret: Option<String>,
+}
+
+//An api token of a user, only the argon2 hash of the secret is stored
+#[derive(Serialize, Deserialize)]
+struct ApiToken {
+    id: String,
+    hash: String,
+    #[serde(default)]
+    description: String,
+    //No expiry if not set
+    expires: Option<DateTime<Utc>>,
+    //Restricts the token to a subset of the user's groups, all groups of the user if not set
+    groups: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,26 +50,39 @@ struct Users {
     users: Vec<User>,
 }
 
+//The parsed users file
+struct UserStore {
+    users: HashMap<String, User>,
+    //Token id -> username, to find the token of an api token without searching all users
+    token_ids: HashMap<String, String>,
+}
+
 //Auther struct for using users.json
 pub struct JsonAuthenticator {
-    users: HashMap<String, User>,
+    users: FileStore<UserStore>,
     hashed_pw: bool,
 }
 
 impl Authenticator for JsonAuthenticator {
-    async fn auth(&self, token: &str) -> (bool, String, Vec<String>) {
-        //Split token by :
-        let token_split: Vec<&str> = token.split(":").collect();
-
-        //Get username
-        let username = token_split[0];
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        //Api tokens are checked against the tokens of the users instead of the passwords
+        //Tokens with : are passwords, so users named kat_... can still log in
+        if !token.contains(":") {
+            if let Some(api_token) = token.strip_prefix(API_TOKEN_PREFIX) {
+                return self.auth_api_token(api_token);
+            }
+        }
 
-        //Get password
-        let password = token_split[1];
+        //Split token by :, tokens without : are not meant for this authenticator
+        let (username, password) = match token.split_once(":") {
+            Some(split) => split,
+            None => return AuthResult::denied(),
+        };
 
         println!("Authenticating user {} with json", username);
-        //Get the users
-        let users = &self.users;
+        //Get the users, reloads the users file if it changed
+        let store = self.users.get();
+        let users = &store.users;
 
         //Check if the user exists
         if users.contains_key(username) {
@@ -64,25 +102,25 @@ impl Authenticator for JsonAuthenticator {
 
                 if hashed_password.is_err() {
                     println!("Failed to hash password");
-                    return (false, String::from(""), vec![]);
+                    return AuthResult::denied();
                 }
 
                 //Check if the password is correct
                 if user.password == hashed_password.unwrap().to_string() {
                     //Return true
-                    return (true, String::from(username), user.groups.clone());
+                    return AuthResult::granted(username, username, user.groups.clone());
                 }
             } else {
                 //Check if the password is correct
                 if user.password == password {
                     //Return true
-                    return (true, String::from(username), user.groups.clone());
+                    return AuthResult::granted(username, username, user.groups.clone());
                 }
             }
         }
 
         //Return false
-        return (false, String::from(""), vec![]);
+        return AuthResult::denied();
     }
 
     
@@ -113,7 +151,13 @@ impl Authenticator for JsonAuthenticator {
         }
         
         //Get the users
-        let users = JsonAuthenticator::get_users(&users_file_path);
+        let users = match FileStore::load(&users_file_path, JsonAuthenticator::get_users) {
+            Ok(users) => users,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
 
         let mut hashed = false;
         //Get --json_hashed_pw argument
@@ -136,26 +180,256 @@ impl Authenticator for JsonAuthenticator {
 }
 
 impl JsonAuthenticator {
-    //Get the users from the users file
-    fn get_users(users_file_path: &str) -> HashMap<String, User> {
-        //Open the users file
-        let users_file = File::open(users_file_path).expect("Failed to open users file");
+    //Get the TOTP secret of a user, used by the pipeline to check the second factor
+    pub fn totp_secret(&self, username: &str) -> Option<String> {
+        self.users.get().users.get(username).and_then(|user| user.totp_secret.clone())
+    }
 
-        //Read the users file
-        let users_file_reader = BufReader::new(users_file);
+    //If any user has a TOTP secret
+    pub fn has_totp_secrets(&self) -> bool {
+        self.users.get().users.values().any(|user| user.totp_secret.is_some())
+    }
 
+    //Parse the users from the content of the users file
+    fn get_users(content: &str) -> Result<UserStore, String> {
         //Deserialize the users file
-        let users: Users = serde_json::from_reader(users_file_reader).expect("Failed to deserialize users file");
+        let users: Users = serde_json::from_str(content)
+            .map_err(|e| format!("Failed to deserialize users file: {}", e))?;
 
         //Create the users map
         let mut users_map: HashMap<String, User> = HashMap::new();
+        let mut token_ids: HashMap<String, String> = HashMap::new();
 
         //Add the users to the users map
         for user in users.users {
+            for token in &user.tokens {
+                //The id is terminated by _ in the token, so it can't contain one, : would make it a password token
+                if token.id.is_empty() || token.id.contains(['_', ':']) {
+                    return Err(format!("Token id {:?} of user {} must be non empty and must not contain _ or :", token.id, user.username));
+                }
+                if token_ids.insert(token.id.clone(), user.username.clone()).is_some() {
+                    return Err(format!("Token id {} is used more than once", token.id));
+                }
+            }
             users_map.insert(user.username.clone(), user);
         }
 
         //Return the users map
-        users_map
+        Ok(UserStore {
+            users: users_map,
+            token_ids,
+        })
     }
-}
\ No newline at end of file
+
+    //Authenticate an api token, the token is <id>_<secret> with the kat_ prefix already removed
+    fn auth_api_token(&self, api_token: &str) -> AuthResult {
+        let (id, secret) = match api_token.split_once("_") {
+            Some(split) => split,
+            None => return AuthResult::denied(),
+        };
+
+        let store = self.users.get();
+
+        //Find the user and the token by the token id
+        let user = match store.token_ids.get(id).and_then(|username| store.users.get(username)) {
+            Some(user) => user,
+            None => {
+                println!("Unknown api token id {}", id);
+                return AuthResult::denied();
+            }
+        };
+        let token = match user.tokens.iter().find(|t| t.id == id) {
+            Some(token) => token,
+            None => return AuthResult::denied(),
+        };
+
+        if let Some(expires) = token.expires {
+            if expires <= Utc::now() {
+                println!("Api token {} of user {} expired at {}", id, user.username, expires);
+                return AuthResult::denied();
+            }
+        }
+
+        //Verify the secret against the stored hash
+        let hash = match PasswordHash::new(&token.hash) {
+            Ok(hash) => hash,
+            Err(e) => {
+                println!("Api token {} of user {} has an invalid hash: {}", id, user.username, e);
+                return AuthResult::denied();
+            }
+        };
+        if Argon2::default().verify_password(secret.as_bytes(), &hash).is_err() {
+            println!("Invalid secret for api token {} of user {}", id, user.username);
+            return AuthResult::denied();
+        }
+
+        //A token can only narrow down the groups of the user, never add to them
+        let groups = match &token.groups {
+            Some(token_groups) => user
+                .groups
+                .iter()
+                .filter(|g| token_groups.contains(g))
+                .cloned()
+                .collect(),
+            None => user.groups.clone(),
+        };
+
+        println!("Authenticated user {} with api token {} ({})", user.username, id, token.description);
+        AuthResult::granted(&user.username, &user.username, groups)
+    }
+
+    //Create a new api token, used by the token create subcommand
+    //Prints the token once and the entry that has to be added to the tokens of the user in the users file
+    pub fn create_api_token(arguments: &HashMap<String, Vec<String>>) {
+        let mut random = [0u8; 4];
+        OsRng.fill_bytes(&mut random);
+        let random_id: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+        //Get --id argument, random if not set
+        let id = match argument_values(arguments, "id") {
+            Some(id) if id.len() == 1 && !id[0].is_empty() && !id[0].contains(['_', ':']) => id[0].clone(),
+            Some(_) => {
+                println!("--id must be one value that doesn't contain _ or :");
+                std::process::exit(1);
+            }
+            None => random_id,
+        };
+
+        //Get --expires argument, rfc3339 timestamp, no expiry if not set
+        let expires = match argument_values(arguments, "expires") {
+            Some(expires) if expires.len() == 1 => match DateTime::parse_from_rfc3339(&expires[0]) {
+                Ok(expires) => Some(expires.with_timezone(&Utc)),
+                Err(e) => {
+                    println!("--expires is not a valid rfc3339 timestamp: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            Some(_) => {
+                println!("--expires must be one rfc3339 timestamp");
+                std::process::exit(1);
+            }
+            None => None,
+        };
+
+        //The secret is never stored, only its hash
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let secret: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = match Argon2::default().hash_password(secret.as_bytes(), &salt) {
+            Ok(hash) => hash.to_string(),
+            Err(e) => {
+                println!("Failed to hash token: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let token = ApiToken {
+            id: id.clone(),
+            hash,
+            description: argument_values(arguments, "description").map(|d| d.join(" ")).unwrap_or_default(),
+            expires,
+            groups: argument_values(arguments, "groups"),
+        };
+
+        println!("Token, it is only shown once:");
+        println!("{}{}_{}", API_TOKEN_PREFIX, id, secret);
+        println!("Add this entry to the tokens of the user in the users file:");
+        println!("{}", serde_json::to_string_pretty(&token).unwrap());
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hash(secret: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(secret.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    fn authenticator(name: &str, users: serde_json::Value) -> JsonAuthenticator {
+        let users_file = std::env::temp_dir().join(format!("kubeauth-json-test-{}-{}.json", std::process::id(), name));
+        std::fs::write(&users_file, users.to_string()).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("json_user_file_path"), vec![users_file.to_string_lossy().to_string()]);
+        JsonAuthenticator::new(arguments)
+    }
+
+    fn service_user() -> serde_json::Value {
+        json!({"users": [{
+            "username": "deploy",
+            "password": "unused",
+            "groups": ["dev", "ops"],
+            "tokens": [
+                {"id": "ci", "hash": hash("c0ffee")},
+                {"id": "ro", "hash": hash("beef"), "groups": ["ops", "admin"]},
+                {"id": "old", "hash": hash("dead"), "expires": "2020-01-01T00:00:00Z"}
+            ]
+        }]})
+    }
+
+    #[rocket::async_test]
+    async fn api_tokens_are_verified_with_argon2() {
+        let json = authenticator("api", service_user());
+
+        let result = json.auth("kat_ci_c0ffee", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "deploy");
+        assert_eq!(result.groups, vec!["dev", "ops"]);
+
+        //The token groups narrow down the groups of the user, admin is not one of them
+        let result = json.auth("kat_ro_beef", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.groups, vec!["ops"]);
+
+        assert!(!json.auth("kat_ci_beef", &[]).await.authenticated);
+        assert!(!json.auth("kat_old_dead", &[]).await.authenticated);
+    }
+
+    #[rocket::async_test]
+    async fn unknown_and_malformed_api_tokens_are_denied() {
+        let json = authenticator("malformed", service_user());
+
+        for token in ["kat_unknown_c0ffee", "kat_ci_", "kat_ci", "kat_ci_c0_ffee", "kat__c0ffee", "kat_", "ci_c0ffee"] {
+            let result = json.auth(token, &[]).await;
+            assert!(!result.authenticated, "{}", token);
+            assert!(result.error.is_none(), "{}", token);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn passwords_are_checked() {
+        let json = authenticator("password", json!({"users": [
+            {"username": "alice", "password": "secret", "groups": ["dev"]},
+            {"username": "kat_bob", "password": "pw_1", "groups": []}
+        ]}));
+
+        let result = json.auth("alice:secret", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.groups, vec!["dev"]);
+        assert!(!json.auth("alice:wrong", &[]).await.authenticated);
+        assert!(!json.auth("mallory:secret", &[]).await.authenticated);
+
+        //A token with : is a password even if the username looks like an api token
+        assert!(json.auth("kat_bob:pw_1", &[]).await.authenticated);
+    }
+
+    #[test]
+    fn invalid_token_ids_fail_the_users_file() {
+        for id in ["", "a_b", "a:b"] {
+            let users = json!({"users": [{"username": "u", "password": "p", "groups": [], "tokens": [{"id": id, "hash": "x"}]}]});
+            assert!(JsonAuthenticator::get_users(&users.to_string()).is_err(), "{}", id);
+        }
+
+        let users = json!({"users": [
+            {"username": "u1", "password": "p", "groups": [], "tokens": [{"id": "t", "hash": "x"}]},
+            {"username": "u2", "password": "p", "groups": [], "tokens": [{"id": "t", "hash": "x"}]}
+        ]});
+        assert_eq!(
+            JsonAuthenticator::get_users(&users.to_string()).err().unwrap(),
+            "Token id t is used more than once"
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use std::{collections::HashMap, path::Path};
+
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk},
+    Algorithm, DecodingKey, Header, Validation,
+};
+use serde_json::Value;
+
+use super::authenticator::{argument_values, AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+/*
+This is a jwt authenticator, it verifies JWTs minted by other services against local keys.
+Keys are read from a JWKS file or from a file with one or more PEM public keys.
+Supported algorithms are RS256, ES256 and EdDSA.
+The key and claim helpers are also used by the oidc authenticator.
+*/
+
+//Default clock skew tolerance for exp and nbf in seconds
+pub const DEFAULT_LEEWAY: u64 = 60;
+
+//A key tokens can be verified with
+pub struct JwtKey {
+    //Key id, PEM keys have none and are tried for every token
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    pub key: DecodingKey,
+}
+
+//Which claims the kubernetes user is taken from, claims are paths like realm_access.roles
+#[derive(Clone)]
+pub struct ClaimMapping {
+    pub username: String,
+    pub uid: String,
+    pub groups: String,
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        ClaimMapping {
+            username: String::from("sub"),
+            uid: String::from("sub"),
+            groups: String::from("groups"),
+        }
+    }
+}
+
+impl ClaimMapping {
+    //Map the claims to username, uid and groups, the username claim is required
+    pub fn map(&self, claims: &Value) -> Option<(String, String, Vec<String>)> {
+        let username = claim_string(claims, &self.username)?;
+        let uid = claim_string(claims, &self.uid).unwrap_or_default();
+        let groups = claim_strings(claims, &self.groups);
+        Some((username, uid, groups))
+    }
+}
+
+//Get a claim by its path, path segments are separated by .
+pub fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut value = claims;
+    for segment in path.split(".") {
+        value = value.get(segment)?;
+    }
+    Some(value)
+}
+
+//Get a claim as string, numbers and booleans are converted
+pub fn claim_string(claims: &Value, path: &str) -> Option<String> {
+    match claim(claims, path)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+//Get a claim as list of strings, a single string is a list with one element
+pub fn claim_strings(claims: &Value, path: &str) -> Vec<String> {
+    match claim(claims, path) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        Some(Value::String(value)) => vec![value.clone()],
+        _ => vec![],
+    }
+}
+
+//Parse a key file, either a JWKS or one or more PEM public keys
+pub fn parse_keys(content: &str) -> Result<Vec<JwtKey>, String> {
+    let keys = if content.trim_start().starts_with("{") {
+        let jwks: Value = serde_json::from_str(content).map_err(|e| format!("Invalid JWKS: {}", e))?;
+        keys_from_jwks(&jwks)
+    } else {
+        keys_from_pem(content)?
+    };
+
+    if keys.is_empty() {
+        return Err(String::from("No supported RS256, ES256 or EdDSA key found"));
+    }
+    Ok(keys)
+}
+
+//Get the supported keys of a JWKS, unsupported keys are skipped
+pub fn keys_from_jwks(jwks: &Value) -> Vec<JwtKey> {
+    let mut keys: Vec<JwtKey> = vec![];
+
+    for value in jwks["keys"].as_array().map(|k| k.as_slice()).unwrap_or(&[]) {
+        //Keys of unknown types would fail the whole set, so they are parsed one by one
+        let jwk: Jwk = match serde_json::from_value(value.clone()) {
+            Ok(jwk) => jwk,
+            Err(_) => continue,
+        };
+
+        let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm.map(|a| a.to_string())) {
+            (_, Some(alg)) if alg == "RS256" || alg == "ES256" || alg == "EdDSA" => {
+                alg.parse::<Algorithm>().unwrap()
+            }
+            (_, Some(_)) => continue,
+            (AlgorithmParameters::RSA(_), None) => Algorithm::RS256,
+            (AlgorithmParameters::EllipticCurve(params), None) if params.curve == EllipticCurve::P256 => {
+                Algorithm::ES256
+            }
+            (AlgorithmParameters::OctetKeyPair(params), None) if params.curve == EllipticCurve::Ed25519 => {
+                Algorithm::EdDSA
+            }
+            _ => continue,
+        };
+
+        if let Ok(key) = DecodingKey::from_jwk(&jwk) {
+            keys.push(JwtKey {
+                kid: jwk.common.key_id.clone(),
+                algorithm,
+                key,
+            });
+        }
+    }
+
+    keys
+}
+
+//Get the keys of all PEM blocks, the algorithm is taken from the key type
+fn keys_from_pem(content: &str) -> Result<Vec<JwtKey>, String> {
+    let mut keys: Vec<JwtKey> = vec![];
+
+    for block in content.split("-----BEGIN").skip(1) {
+        let pem = format!("-----BEGIN{}", block);
+        let pem = pem.as_bytes();
+
+        let key = if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+            (Algorithm::RS256, key)
+        } else if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+            (Algorithm::ES256, key)
+        } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+            (Algorithm::EdDSA, key)
+        } else {
+            return Err(String::from("PEM block is not a RSA, EC P-256 or Ed25519 public key"));
+        };
+
+        keys.push(JwtKey {
+            kid: None,
+            algorithm: key.0,
+            key: key.1,
+        });
+    }
+
+    Ok(keys)
+}
+
+//Find the keys a token may be signed with, by kid if the token has one and the key algorithm
+pub fn candidate_keys<'a>(keys: &'a [JwtKey], header: &Header) -> Vec<&'a JwtKey> {
+    keys.iter()
+        .filter(|key| key.algorithm == header.alg)
+        .filter(|key| match (&header.kid, &key.kid) {
+            (Some(kid), Some(key_kid)) => kid == key_kid,
+            _ => true,
+        })
+        .collect()
+}
+
+//Verify a token with the candidate keys, returns the claims of the token
+pub fn verify(token: &str, keys: &[&JwtKey], validation: &Validation) -> Result<Value, String> {
+    let mut error = String::from("no matching key");
+    for key in keys {
+        match jsonwebtoken::decode::<Value>(token, &key.key, validation) {
+            Ok(data) => return Ok(data.claims),
+            Err(e) => error = e.to_string(),
+        }
+    }
+    Err(error)
+}
+
+//The audiences of a token that are also wanted
+pub fn matched_audiences(claims: &Value, wanted: &[String]) -> Vec<String> {
+    claim_strings(claims, "aud")
+        .into_iter()
+        .filter(|aud| wanted.contains(aud))
+        .collect()
+}
+
+//Auther struct for JWTs verified against local keys
+pub struct JwtAuthenticator {
+    keys: FileStore<Vec<JwtKey>>,
+    issuer: String,
+    //Used if the TokenReview has no audiences
+    audiences: Vec<String>,
+    leeway: u64,
+    claims: ClaimMapping,
+}
+
+impl Authenticator for JwtAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        //Only JWTs are meant for this authenticator
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(_) => return AuthResult::denied(),
+        };
+
+        //The audiences of the TokenReview take precedence over the configured ones
+        let wanted = if audiences.is_empty() { &self.audiences[..] } else { audiences };
+        if wanted.is_empty() {
+            println!("No audiences to check the jwt against, set --jwt_audiences");
+            return AuthResult::denied();
+        }
+
+        let keys = self.keys.get();
+        let candidates = candidate_keys(&keys, &header);
+        if candidates.is_empty() {
+            println!("No key for jwt with alg {:?} and kid {:?}", header.alg, header.kid);
+            return AuthResult::denied();
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(wanted);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway;
+
+        let claims = match verify(token, &candidates, &validation) {
+            Ok(claims) => claims,
+            Err(e) => {
+                println!("Invalid jwt: {}", e);
+                return AuthResult::denied();
+            }
+        };
+
+        match self.claims.map(&claims) {
+            Some((username, uid, groups)) => {
+                println!("Authenticated user {} with jwt", username);
+                let mut result = AuthResult::granted(&username, &uid, groups);
+                result.audiences = matched_audiences(&claims, wanted);
+                result
+            }
+            None => {
+                println!("jwt has no {} claim", self.claims.username);
+                AuthResult::denied()
+            }
+        }
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading jwt_auth authenticator");
+
+        //Get --jwt_keys_file argument, required
+        let keys_file = match arguments.get("jwt_keys_file") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--jwt_keys_file is required for jwt_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        if !Path::new(&keys_file).exists() {
+            println!("--jwt_keys_file is not valid");
+            std::process::exit(1);
+        }
+        let keys = match FileStore::load(&keys_file, parse_keys) {
+            Ok(keys) => keys,
+            Err(e) => {
+                println!("--jwt_keys_file {}: {}", keys_file, e);
+                std::process::exit(1);
+            }
+        };
+
+        //Get --jwt_issuer argument, required
+        let issuer = match arguments.get("jwt_issuer") {
+            Some(issuer) if !issuer.is_empty() => issuer[0].clone(),
+            _ => {
+                println!("--jwt_issuer is required for jwt_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Get --jwt_leeway argument in seconds
+        let leeway = match arguments.get("jwt_leeway") {
+            Some(leeway) => match leeway[0].parse::<u64>() {
+                Ok(leeway) => leeway,
+                Err(_) => {
+                    println!("--jwt_leeway is not a valid number of seconds");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_LEEWAY,
+        };
+
+        //Get the claim mapping arguments
+        let mut claims = ClaimMapping::default();
+        if let Some(claim) = arguments.get("jwt_username_claim") {
+            claims.username = claim[0].clone();
+        }
+        if let Some(claim) = arguments.get("jwt_uid_claim") {
+            claims.uid = claim[0].clone();
+        }
+        if let Some(claim) = arguments.get("jwt_groups_claim") {
+            claims.groups = claim[0].clone();
+        }
+
+        JwtAuthenticator {
+            keys,
+            issuer,
+            audiences: argument_values(&arguments, "jwt_audiences").unwrap_or_default(),
+            leeway,
+            claims,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+    use jsonwebtoken::EncodingKey;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn audiences_may_be_a_string_or_an_array() {
+        let wanted = strings(&["kubeauth", "other"]);
+        assert_eq!(matched_audiences(&json!({"aud": "kubeauth"}), &wanted), vec!["kubeauth"]);
+        assert_eq!(
+            matched_audiences(&json!({"aud": ["api", "other", "kubeauth"]}), &wanted),
+            vec!["other", "kubeauth"]
+        );
+        assert!(matched_audiences(&json!({"aud": "api"}), &wanted).is_empty());
+        assert!(matched_audiences(&json!({"sub": "alice"}), &wanted).is_empty());
+    }
+
+    #[test]
+    fn claims_are_found_by_path() {
+        let claims = json!({
+            "sub": "alice",
+            "uid": 1001,
+            "admin": true,
+            "realm_access": {"roles": ["dev", 7, "ops"], "primary": "dev"},
+            "resource.access": "dotted"
+        });
+        assert_eq!(claim_string(&claims, "sub").as_deref(), Some("alice"));
+        assert_eq!(claim_string(&claims, "uid").as_deref(), Some("1001"));
+        assert_eq!(claim_string(&claims, "admin").as_deref(), Some("true"));
+        assert_eq!(claim_string(&claims, "realm_access.primary").as_deref(), Some("dev"));
+        //Objects and arrays are no strings, segments are always split at .
+        assert_eq!(claim_string(&claims, "realm_access"), None);
+        assert_eq!(claim_string(&claims, "realm_access.roles"), None);
+        assert_eq!(claim_string(&claims, "realm_access.missing"), None);
+        assert_eq!(claim_string(&claims, "resource.access"), None);
+
+        assert_eq!(claim_strings(&claims, "realm_access.roles"), vec!["dev", "ops"]);
+        assert_eq!(claim_strings(&claims, "realm_access.primary"), vec!["dev"]);
+        assert!(claim_strings(&claims, "uid").is_empty());
+    }
+
+    #[test]
+    fn symmetric_keys_are_not_loaded() {
+        let jwks = json!({"keys": [
+            {"kty": "oct", "kid": "hs", "alg": "HS256", "k": BASE64_URL.encode(b"secret")},
+            {"kty": "oct", "kid": "hs-no-alg", "k": BASE64_URL.encode(b"secret")}
+        ]});
+        assert!(keys_from_jwks(&jwks).is_empty());
+        assert_eq!(
+            parse_keys(&jwks.to_string()).err().unwrap(),
+            "No supported RS256, ES256 or EdDSA key found"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn none_and_hmac_tokens_are_denied() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwks = json!({"keys": [{"kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "kid": "k1", "x": BASE64_URL.encode(pair.public_key().as_ref())}]});
+        let keys_file = std::env::temp_dir().join(format!("kubeauth-jwt-test-{}.json", std::process::id()));
+        std::fs::write(&keys_file, jwks.to_string()).unwrap();
+
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("jwt_keys_file"), vec![keys_file.to_string_lossy().to_string()]);
+        arguments.insert(String::from("jwt_issuer"), strings(&["https://issuer.example"]));
+        arguments.insert(String::from("jwt_audiences"), strings(&["kubeauth"]));
+        let jwt = JwtAuthenticator::new(arguments);
+
+        let now = chrono::Utc::now().timestamp();
+        let claims = json!({"iss": "https://issuer.example", "aud": "kubeauth", "sub": "alice", "exp": now + 300});
+
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(String::from("k1"));
+        let valid = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(pkcs8.as_ref())).unwrap();
+        assert!(jwt.auth(&valid, &[]).await.authenticated);
+
+        //alg none, the signature is empty
+        let none = format!(
+            "{}.{}.",
+            BASE64_URL.encode(json!({"alg": "none", "kid": "k1"}).to_string()),
+            BASE64_URL.encode(claims.to_string())
+        );
+        assert!(!jwt.auth(&none, &[]).await.authenticated);
+
+        //HS256 signed with the public key as secret, the classic key confusion attack
+        for alg in [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512] {
+            let mut header = Header::new(alg);
+            header.kid = Some(String::from("k1"));
+            let hmac = jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(pair.public_key().as_ref())).unwrap();
+            assert!(!jwt.auth(&hmac, &[]).await.authenticated, "{:?}", alg);
+        }
+    }
+}
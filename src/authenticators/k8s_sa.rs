@@ -0,0 +1,338 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use serde_json::Value;
+
+use super::authenticator::{argument_values, AuthResult, Authenticator};
+use super::kubeconfig::{self, KubeEndpoint};
+use crate::token_review::{TokenRequest, TokenReviewRequestSpec, TokenReviewResponse};
+
+/*
+This is a ServiceAccount authenticator, it checks ServiceAccount tokens of another cluster
+by sending a TokenReview to the API server of that cluster.
+Only ServiceAccounts of the allowed namespaces (and names) are accepted. They get a new username,
+so they can't be mistaken for the ServiceAccounts of this cluster, and the configured groups.
+*/
+
+//Timeout of the requests to the remote API server
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//Default username of the remote ServiceAccounts
+const DEFAULT_USERNAME_TEMPLATE: &str = "system:remote-serviceaccount:${namespace}:${name}";
+
+//Cached results are dropped once there are this many, so random tokens can't fill the memory
+const MAX_CACHE_ENTRIES: usize = 10000;
+
+//Legacy tokens of ServiceAccount Secrets have no exp, they are valid until the Secret is deleted
+//Their results are only cached for this many seconds, so a deleted Secret takes effect soon
+const LEGACY_TOKEN_CACHE_TTL: u64 = 30;
+
+//Prefix of the usernames of ServiceAccounts
+const SERVICE_ACCOUNT_PREFIX: &str = "system:serviceaccount:";
+
+//A cached positive result, valid until the token expires or LEGACY_TOKEN_CACHE_TTL for tokens without exp
+struct CacheEntry {
+    result: AuthResult,
+    expires: u64,
+}
+
+//Auther struct for ServiceAccount tokens of a remote cluster
+pub struct K8sSaAuthenticator {
+    endpoint: KubeEndpoint,
+    namespaces: Vec<String>,
+    //Empty allows every ServiceAccount of the namespaces
+    names: Vec<String>,
+    username_template: String,
+    groups: Vec<String>,
+    //Audiences the tokens are reviewed for, the TokenReview audiences are used if empty
+    audiences: Vec<String>,
+    //audiences and token -> result
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Authenticator for K8sSaAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        //ServiceAccount tokens are JWTs, the expiry is needed for the cache
+        let claims = match K8sSaAuthenticator::unverified_claims(token) {
+            Some(claims) => claims,
+            None => return AuthResult::denied(),
+        };
+        //Legacy tokens have no exp, the remote API server checks that their Secret still exists
+        let expires = match claims.get("exp") {
+            Some(exp) => match exp.as_u64() {
+                Some(exp) if exp > now() => exp,
+                _ => return AuthResult::denied(),
+            },
+            None => now() + LEGACY_TOKEN_CACHE_TTL,
+        };
+
+        let review_audiences = if self.audiences.is_empty() { audiences } else { &self.audiences[..] };
+        let cache_key = format!("{}\n{}", review_audiences.join(","), token);
+        if let Some(result) = self.cached(&cache_key) {
+            return result;
+        }
+
+        let response = match self.review(token, review_audiences).await {
+            Ok(response) => response,
+            //An API server that can't be reached is no denial, the apiserver must not cache it
+            Err(e) => {
+                println!("Remote TokenReview failed: {}", e);
+                return AuthResult::failed("k8s_sa_auth: remote API server unavailable");
+            }
+        };
+
+        let status = response.status;
+        if !status.authenticated {
+            return AuthResult::denied();
+        }
+
+        //Only ServiceAccounts are accepted, not users of the remote cluster
+        let (namespace, name) = match status
+            .user
+            .username
+            .strip_prefix(SERVICE_ACCOUNT_PREFIX)
+            .and_then(|sa| sa.split_once(":"))
+        {
+            Some(sa) => sa,
+            None => {
+                println!("Remote user {} is not a ServiceAccount", status.user.username);
+                return AuthResult::denied();
+            }
+        };
+
+        if !self.namespaces.iter().any(|n| n == namespace)
+            || (!self.names.is_empty() && !self.names.iter().any(|n| n == name))
+        {
+            println!("ServiceAccount {}/{} is not allowed", namespace, name);
+            return AuthResult::denied();
+        }
+
+        let username = self
+            .username_template
+            .replace("${namespace}", namespace)
+            .replace("${name}", name);
+
+        println!("Authenticated ServiceAccount {}/{} as {}", namespace, name, username);
+        //The groups of the remote cluster are dropped, they would match the groups of local ServiceAccounts
+        let mut result = AuthResult::granted(&username, &status.user.uid, self.groups.clone());
+        //Audiences of the remote cluster mean nothing to the apiserver asking kubeauth
+        if self.audiences.is_empty() {
+            result.audiences = status.audiences;
+        }
+
+        self.cache(cache_key, &result, expires);
+        result
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading k8s_sa_auth authenticator");
+
+        //Get --k8s_sa_kubeconfig argument, required
+        let kubeconfig_file = match arguments.get("k8s_sa_kubeconfig") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--k8s_sa_kubeconfig is required for k8s_sa_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        let endpoint = match kubeconfig::load(&kubeconfig_file, REQUEST_TIMEOUT) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                println!("--k8s_sa_kubeconfig: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        //Get --k8s_sa_namespaces argument, required, otherwise every ServiceAccount of the remote cluster would be let in
+        let namespaces = match argument_values(&arguments, "k8s_sa_namespaces") {
+            Some(namespaces) if !namespaces.is_empty() => namespaces,
+            _ => {
+                println!("--k8s_sa_namespaces is required for k8s_sa_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Get --k8s_sa_username_template argument
+        let username_template = match arguments.get("k8s_sa_username_template") {
+            Some(template) if !template.is_empty() => template[0].clone(),
+            _ => DEFAULT_USERNAME_TEMPLATE.to_string(),
+        };
+        if username_template.starts_with(SERVICE_ACCOUNT_PREFIX) {
+            println!("WARNING: --k8s_sa_username_template {} makes remote ServiceAccounts look like local ones", username_template);
+        }
+
+        K8sSaAuthenticator {
+            endpoint,
+            namespaces,
+            names: argument_values(&arguments, "k8s_sa_names").unwrap_or_default(),
+            username_template,
+            groups: argument_values(&arguments, "k8s_sa_groups").unwrap_or_default(),
+            audiences: argument_values(&arguments, "k8s_sa_audiences").unwrap_or_default(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl K8sSaAuthenticator {
+    //Post a TokenReview to the remote API server
+    async fn review(&self, token: &str, audiences: &[String]) -> Result<TokenReviewResponse, String> {
+        let url = format!("{}/apis/authentication.k8s.io/v1/tokenreviews", self.endpoint.server);
+        let review = TokenRequest {
+            apiVersion: String::from("authentication.k8s.io/v1"),
+            kind: String::from("TokenReview"),
+            spec: TokenReviewRequestSpec {
+                token: token.to_string(),
+                audiences: audiences.to_vec(),
+            },
+        };
+
+        let mut request = self.endpoint.client.post(&url).json(&review);
+        if let Some(token) = &self.endpoint.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("request to {} failed: {}", url, e))?;
+
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()));
+        }
+
+        response
+            .json::<TokenReviewResponse>()
+            .await
+            .map_err(|e| format!("invalid TokenReview from {}: {}", url, e))
+    }
+
+    //Read the claims without verifying the token, the remote API server verifies it
+    fn unverified_claims(token: &str) -> Option<Value> {
+        let payload = token.split(".").nth(1)?;
+        let payload = BASE64_URL.decode(payload).ok()?;
+        let claims: Value = serde_json::from_slice(&payload).ok()?;
+        claims.is_object().then_some(claims)
+    }
+
+    fn cached(&self, key: &str) -> Option<AuthResult> {
+        let cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some(entry) if entry.expires > now() => Some(entry.result.clone()),
+            _ => None,
+        }
+    }
+
+    fn cache(&self, key: String, result: &AuthResult, expires: u64) {
+        let mut cache = self.cache.lock().unwrap();
+        let now = now();
+        cache.retain(|_, entry| entry.expires > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.clear();
+        }
+        cache.insert(
+            key,
+            CacheEntry {
+                result: result.clone(),
+                expires,
+            },
+        );
+    }
+}
+
+//Seconds since the epoch
+fn now() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::authenticators::test_server::TestServer;
+
+    fn authenticator(server: &str) -> K8sSaAuthenticator {
+        let port = server.rsplit(":").next().unwrap();
+        let path = std::env::temp_dir().join(format!("kubeauth-k8s-sa-test-{}.yaml", port));
+        let kubeconfig = format!(
+            "apiVersion: v1\nkind: Config\ncurrent-context: remote\nclusters:\n- name: remote\n  cluster:\n    server: {}\nusers:\n- name: reviewer\n  user:\n    token: reviewer-token\ncontexts:\n- name: remote\n  context:\n    cluster: remote\n    user: reviewer\n",
+            server
+        );
+        fs::write(&path, kubeconfig).unwrap();
+        let mut arguments = HashMap::new();
+        arguments.insert(String::from("k8s_sa_kubeconfig"), vec![path.to_string_lossy().to_string()]);
+        arguments.insert(String::from("k8s_sa_namespaces"), vec![String::from("ci")]);
+        arguments.insert(String::from("k8s_sa_groups"), vec![String::from("deployers")]);
+        K8sSaAuthenticator::new(arguments)
+    }
+
+    //An unsigned JWT, the stub API server doesn't check signatures
+    fn token(claims: Value) -> String {
+        format!("e30.{}.sig", BASE64_URL.encode(claims.to_string()))
+    }
+
+    //A stub API server, the token is the ServiceAccount username
+    fn start_server() -> TestServer {
+        TestServer::start(|request| {
+            let review: Value = serde_json::from_str(&request.body).unwrap();
+            let token = review["spec"]["token"].as_str().unwrap();
+            let payload = BASE64_URL.decode(token.split(".").nth(1).unwrap()).unwrap();
+            let claims: Value = serde_json::from_slice(&payload).unwrap();
+            let username = claims["sub"].as_str().unwrap_or_default();
+            let status = json!({"authenticated": !username.is_empty(), "user": {"username": username, "uid": "sa-uid", "groups": ["system:serviceaccounts"]}});
+            (201, json!({"apiVersion": "authentication.k8s.io/v1", "kind": "TokenReview", "status": status}).to_string())
+        })
+    }
+
+    #[rocket::async_test]
+    async fn projected_tokens_are_reviewed_and_cached() {
+        let server = start_server();
+        let k8s_sa = authenticator(&server.url);
+        let token = token(json!({"sub": "system:serviceaccount:ci:deployer", "exp": now() + 300}));
+
+        let result = k8s_sa.auth(&token, &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "system:remote-serviceaccount:ci:deployer");
+        assert_eq!(result.uid, "sa-uid");
+        assert_eq!(result.groups, vec!["deployers"]);
+        assert_eq!(server.requests()[0].path, "/apis/authentication.k8s.io/v1/tokenreviews");
+        assert_eq!(server.requests()[0].headers["authorization"], "Bearer reviewer-token");
+
+        assert!(k8s_sa.auth(&token, &[]).await.authenticated);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn legacy_tokens_without_exp_are_reviewed() {
+        let server = start_server();
+        let k8s_sa = authenticator(&server.url);
+        let token = token(json!({"sub": "system:serviceaccount:ci:legacy", "kubernetes.io/serviceaccount/namespace": "ci"}));
+
+        assert!(k8s_sa.auth(&token, &[]).await.authenticated);
+        let cache = k8s_sa.cache.lock().unwrap();
+        let entry = cache.values().next().unwrap();
+        assert!(entry.expires <= now() + LEGACY_TOKEN_CACHE_TTL);
+    }
+
+    #[rocket::async_test]
+    async fn expired_foreign_and_unchecked_tokens_are_not_accepted() {
+        let server = start_server();
+        let k8s_sa = authenticator(&server.url);
+
+        let expired = token(json!({"sub": "system:serviceaccount:ci:deployer", "exp": now() - 10}));
+        assert!(!k8s_sa.auth(&expired, &[]).await.authenticated);
+        let other_namespace = token(json!({"sub": "system:serviceaccount:prod:deployer", "exp": now() + 300}));
+        assert!(!k8s_sa.auth(&other_namespace, &[]).await.authenticated);
+        let user = token(json!({"sub": "alice", "exp": now() + 300}));
+        assert!(!k8s_sa.auth(&user, &[]).await.authenticated);
+        assert!(!k8s_sa.auth("not-a-jwt", &[]).await.authenticated);
+
+        //Nothing listens on the port of a dropped listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let unreachable = authenticator(&format!("http://127.0.0.1:{}", port));
+        let result = unreachable.auth(&token(json!({"sub": "system:serviceaccount:ci:deployer"})), &[]).await;
+        assert!(result.error.is_some());
+    }
+}
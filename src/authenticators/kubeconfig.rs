@@ -0,0 +1,172 @@
+use std::{fs, path::Path, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+
+/*
+Minimal kubeconfig support for authenticators that talk to other kubernetes style endpoints.
+Kubeconfigs are read as YAML, which also accepts the JSON form (kubectl config view -o json).
+The current context is used, like kubectl does.
+*/
+
+#[derive(Deserialize)]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(rename = "current-context", default)]
+    current_context: String,
+}
+
+#[derive(Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: Cluster,
+}
+
+#[derive(Deserialize)]
+struct Cluster {
+    server: String,
+    #[serde(rename = "certificate-authority")]
+    certificate_authority: Option<String>,
+    #[serde(rename = "certificate-authority-data")]
+    certificate_authority_data: Option<String>,
+    #[serde(rename = "insecure-skip-tls-verify", default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Deserialize)]
+struct NamedUser {
+    name: String,
+    user: User,
+}
+
+#[derive(Deserialize, Default)]
+struct User {
+    #[serde(rename = "client-certificate")]
+    client_certificate: Option<String>,
+    #[serde(rename = "client-certificate-data")]
+    client_certificate_data: Option<String>,
+    #[serde(rename = "client-key")]
+    client_key: Option<String>,
+    #[serde(rename = "client-key-data")]
+    client_key_data: Option<String>,
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NamedContext {
+    name: String,
+    context: Context,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    cluster: String,
+    #[serde(default)]
+    user: String,
+}
+
+//The endpoint and credentials of the current context
+pub struct KubeEndpoint {
+    pub server: String,
+    pub client: reqwest::Client,
+    //Bearer token of the user, if it authenticates with a token
+    pub token: Option<String>,
+}
+
+//Load a kubeconfig file and create a client for its current context
+pub fn load(path: &str, timeout: Duration) -> Result<KubeEndpoint, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let config: Kubeconfig = serde_yaml::from_str(&content)
+        .map_err(|e| format!("{} is not a valid kubeconfig: {}", path, e))?;
+    //Relative paths are relative to the kubeconfig, like in kubectl
+    let base = Path::new(path).parent().unwrap_or(Path::new("."));
+
+    //Without contexts a kubeconfig with a single cluster and user is still usable
+    let (cluster_name, user_name) = match config.contexts.iter().find(|c| c.name == config.current_context) {
+        Some(context) => (context.context.cluster.clone(), context.context.user.clone()),
+        None if config.clusters.len() == 1 => (
+            config.clusters[0].name.clone(),
+            config.users.first().map(|u| u.name.clone()).unwrap_or_default(),
+        ),
+        None => return Err(format!("{} has no current-context", path)),
+    };
+
+    let cluster = match config.clusters.iter().find(|c| c.name == cluster_name) {
+        Some(cluster) => &cluster.cluster,
+        None => return Err(format!("{} has no cluster {}", path, cluster_name)),
+    };
+    let default_user = User::default();
+    let user = config
+        .users
+        .iter()
+        .find(|u| u.name == user_name)
+        .map(|u| &u.user)
+        .unwrap_or(&default_user);
+
+    let mut client = reqwest::Client::builder().timeout(timeout);
+
+    if let Some(ca) = read_data(base, &cluster.certificate_authority, &cluster.certificate_authority_data)? {
+        let certificate = reqwest::Certificate::from_pem(&ca).map_err(|e| format!("invalid certificate authority: {}", e))?;
+        client = client.add_root_certificate(certificate);
+    }
+    if cluster.insecure_skip_tls_verify {
+        println!("WARNING: {} skips the tls verification of {}", path, cluster.server);
+        client = client.danger_accept_invalid_certs(true);
+    }
+
+    let certificate = read_data(base, &user.client_certificate, &user.client_certificate_data)?;
+    let key = read_data(base, &user.client_key, &user.client_key_data)?;
+    match (certificate, key) {
+        (Some(mut certificate), Some(key)) => {
+            //reqwest wants the certificate and the key in one PEM
+            certificate.push(b'\n');
+            certificate.extend_from_slice(&key);
+            let identity = reqwest::Identity::from_pem(&certificate).map_err(|e| format!("invalid client certificate: {}", e))?;
+            client = client.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err(format!("{} needs both a client certificate and a client key", path)),
+    }
+
+    let token = match (&user.token, &user.token_file) {
+        (Some(token), _) => Some(token.clone()),
+        (None, Some(token_file)) => Some(
+            fs::read_to_string(base.join(token_file))
+                .map_err(|e| format!("failed to read tokenFile {}: {}", token_file, e))?
+                .trim()
+                .to_string(),
+        ),
+        (None, None) => None,
+    };
+
+    let client = client.build().map_err(|e| format!("failed to create http client: {}", e))?;
+
+    Ok(KubeEndpoint {
+        server: cluster.server.trim_end_matches("/").to_string(),
+        client,
+        token,
+    })
+}
+
+//Read a file or base64 data field, the data field wins like in kubectl
+fn read_data(base: &Path, file: &Option<String>, data: &Option<String>) -> Result<Option<Vec<u8>>, String> {
+    if let Some(data) = data {
+        return BASE64
+            .decode(data.trim())
+            .map(Some)
+            .map_err(|e| format!("invalid base64 data: {}", e));
+    }
+    match file {
+        Some(file) => fs::read(base.join(file))
+            .map(Some)
+            .map_err(|e| format!("failed to read {}: {}", file, e)),
+        None => Ok(None),
+    }
+}
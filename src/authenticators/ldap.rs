@@ -1,178 +1,720 @@
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, fs, time::Duration};
 
+use super::authenticator::{replace_vars_escaped, AuthResult, Authenticator};
+use super::ldap_pool::{Failover, LdapFailure, LdapPool, PoolLimits};
 
-
-use serde::{Deserialize, Serialize};
-
-use super::authenticator::Authenticator;
-
-use ldap3::{LdapConnAsync, Scope, SearchEntry};
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnSettings, Scope, SearchEntry};
+use native_tls::{Certificate, Identity, Protocol, TlsConnector};
 /*
 This is a ldap authenticator that can be used to authenticate users using ldap.
 Using ldap3 lib from crates.io
+The user is bound with a DN built from a template (template mode), or with the DN found
+by searching the user with the service account (search mode).
+Searches use pooled connections of the service account, passwords are checked with a bind
+on a separate connection (see ldap_pool.rs).
+The groups are the memberOf attribute of the user or the result of a group search, nested groups
+are resolved by kubeauth or by the server with the in-chain matching rule of Active Directory.
+The active_directory preset changes the defaults for Active Directory: users log in as user,
+user@domain or DOMAIN\user, are found by userPrincipalName or sAMAccountName, identified by their objectGUID and
+get the groups of their tokenGroups.
+The username, uid and extra of the user are read from configurable attributes of the user entry,
+they are fetched in the same search that finds the user.
 */
 
-fn replace_vars(filter: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = String::new();
-    let mut chars = filter.chars();
-    while let Some(c) = chars.next() {
-        if c == '$' {
-            if let Some('{') = chars.next() {
-                let mut var_name = String::new();
-                while let Some(c) = chars.next() {
-                    if c == '}' {
-                        if let Some(val) = vars.get(&var_name[..]) {
-                            result.push_str(val);
-                        }
-                        break;
-                    }
-                    var_name.push(c);
-                }
-            } else {
-                result.push('$');
-            }
+//Matching rule of Active Directory that follows the member attributes of nested groups
+const MATCHING_RULE_IN_CHAIN: &str = "1.2.840.113556.1.4.1941";
+
+//Nested groups are followed this deep, so cycles and very deep trees end
+const MAX_GROUP_DEPTH: usize = 10;
+
+//How the DN of the user is found for the bind
+#[derive(PartialEq)]
+enum BindMode {
+    //The DN is built from LDAP_USER_DN_TEMPLATE
+    Template,
+    //The DN is searched with LDAP_FILTER using the service account
+    Search,
+}
+
+//Where the groups of the user come from
+#[derive(PartialEq)]
+enum GroupMode {
+    //The memberOf attribute of the user
+    MemberOf,
+    //Groups found with LDAP_GROUP_FILTER
+    Search,
+    //The ou= parts of the DN of the user, only for old setups
+    Ou,
+    //The tokenGroups attribute of Active Directory, it contains the nested groups too
+    TokenGroups,
+}
+
+//How nested groups are resolved
+#[derive(PartialEq)]
+enum NestedGroups {
+    None,
+    //kubeauth looks up the groups of the groups
+    Recursive,
+    //The server follows the nesting with MATCHING_RULE_IN_CHAIN
+    InChain,
+}
+
+//How the user entry is mapped to the username, uid and extra
+struct UserMapping {
+    //Attributes of the username and uid, None uses the login name
+    username_attribute: Option<String>,
+    uid_attribute: Option<String>,
+    //extra key -> attribute
+    extra_attributes: Vec<(String, String)>,
+    //Lowercase the username, the group names and the extra values
+    lowercase: bool,
+    //Trim whitespace of the mapped values
+    trim: bool,
+}
+
+impl UserMapping {
+    //Apply the trimming and lowercasing of the mapping to a value
+    fn normalize(&self, value: &str) -> String {
+        let value = if self.trim { value.trim() } else { value };
+        if self.lowercase {
+            value.to_lowercase()
         } else {
-            result.push(c);
+            value.to_string()
         }
     }
-    result
+
+    //The user with the username, uid and extra from the mapped attributes, the login name is used without attributes
+    //A missing username or uid attribute is an error, a missing extra attribute is left out
+    fn map(&self, entry: &SearchEntry, login: &str, groups: Vec<String>) -> Result<AuthResult, String> {
+        let username = match &self.username_attribute {
+            Some(attribute) => self.normalize(&self.mapped_value(entry, attribute)?),
+            None => self.normalize(login),
+        };
+        if username.is_empty() {
+            return Err(String::from("username is empty"));
+        }
+
+        //The uid is only trimmed, it identifies the user and isn't shown
+        let uid = match &self.uid_attribute {
+            //objectGUID is binary, formatted like Active Directory does
+            Some(attribute) if attribute.eq_ignore_ascii_case("objectGUID") => attribute_bytes(entry, attribute)
+                .first()
+                .and_then(|guid| format_guid(guid))
+                .ok_or(format!("{} is missing or not a GUID", attribute))?,
+            Some(attribute) => self.mapped_value(entry, attribute)?,
+            None => username.clone(),
+        };
+
+        let mut extra: HashMap<String, Vec<String>> = HashMap::new();
+        for (key, attribute) in &self.extra_attributes {
+            let values: Vec<String> = attribute_values(entry, attribute)
+                .iter()
+                .map(|value| self.normalize(value))
+                .filter(|value| !value.is_empty())
+                .collect();
+            if !values.is_empty() {
+                extra.insert(key.clone(), values);
+            }
+        }
+        let mut result = AuthResult::granted(&username, &uid, groups);
+        result.extra = extra;
+        Ok(result)
+    }
+
+    //The first value of an attribute of the user, trimmed if enabled
+    fn mapped_value(&self, entry: &SearchEntry, attribute: &str) -> Result<String, String> {
+        let value = attribute_values(entry, attribute).into_iter().next().unwrap_or_default();
+        let value = if self.trim { value.trim().to_string() } else { value };
+        if value.is_empty() {
+            return Err(format!("attribute {} is missing or empty", attribute));
+        }
+        Ok(value)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
 pub struct LdapAuthenticator {
-    ldap_server_url: String,
-    service_account_username: String,
-    service_account_password: String,
+    pool: LdapPool,
     base_dn: String,
+    bind_mode: BindMode,
+    user_dn_template: String,
+    filter: String,
+    group_mode: GroupMode,
+    group_base_dn: String,
+    group_filter: String,
+    group_name_attribute: String,
+    nested_groups: NestedGroups,
+    mapping: UserMapping,
+    //Active Directory logins
+    active_directory: bool,
+    //Domains allowed in user@domain and DOMAIN\user logins, lowercase, all if empty
+    ad_domains: Vec<String>,
+    //The filter to find user@domain logins in search mode with the active_directory preset
+    upn_filter: String,
 }
 
 //Default return value for ldap authenticator
 //const default_return: (bool, String, Vec<String>) = (false, String::from(""), vec![]);
 
 impl Authenticator for LdapAuthenticator {
-    async fn auth(&self, token: &str) -> (bool, String, Vec<String>) {
-        //Split token by :
-        let token_split: Vec<&str> = token.split(":").collect();
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        //Split token by :, tokens without : are not meant for this authenticator
+        let (username, password) = match token.split_once(":") {
+            Some((username, password)) => (String::from(username), password),
+            None => return AuthResult::denied(),
+        };
 
-        //Get username
-        let username = String::from(token_split[0]);
+        //The username is escaped in filters and DNs, only names no directory has are denied
+        if !LdapAuthenticator::is_valid_username(&username) {
+            println!("Username is empty, too long or contains control characters, aborting");
+            return AuthResult::denied();
+        }
 
-        //Check if username is alphanumeric
-        if !LdapAuthenticator::contains_only_alphanumeric(username.clone()) {
-            println!("Username contains non alphanumeric characters, aborting, maybe ldap injection attempt");
-            return (false, String::from(""), vec![]);
+        //A bind with an empty password is an unauthenticated bind, most servers accept it for every DN
+        if password.is_empty() {
+            return AuthResult::denied();
         }
 
-        //Get password
-        let password = token_split[1];
+        //Active Directory users are searched by their userPrincipalName or by the account name without the domain
+        let (account, upn) = if self.active_directory {
+            match self.ad_account_name(&username) {
+                Some(names) => names,
+                None => {
+                    println!("User {} has no account name or a domain that is not in LDAP_AD_DOMAINS", username);
+                    return AuthResult::denied();
+                }
+            }
+        } else {
+            (username.clone(), None)
+        };
 
-        //Create ldap connection
-        let (conn, mut ldap) = LdapConnAsync::new(&self.ldap_server_url).await.unwrap();
-        ldap3::drive!(conn);
+        //Get a connection of the service account for the searches
+        let mut ldap = match self.pool.service_connection().await {
+            Ok(ldap) => ldap,
+            Err(e) => {
+                println!("Failed to get a ldap connection: {}", e);
+                return AuthResult::failed("ldap_auth: ldap server unavailable");
+            }
+        };
 
-        //bind string format
-        let bind_string = format!("cn={},{}", username, &self.base_dn);
+        let entry = match self.bind_mode {
+            BindMode::Template => self.template_bind(&mut ldap, &account, password).await,
+            BindMode::Search => self.search_bind(&mut ldap, &account, upn.as_deref(), password).await,
+        };
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(LdapFailure::Rejected(e)) => {
+                println!("ldap authentication of user {} failed: {}", username, e);
+                return AuthResult::denied();
+            }
+            Err(LdapFailure::Backend(e)) => {
+                println!("ldap authentication of user {} failed, ldap server error: {}", username, e);
+                return AuthResult::failed("ldap_auth: ldap server error");
+            }
+        };
 
-        //try to bind to user and check if it is successful, to check if username and password are correct
-        let bind = ldap.simple_bind(&bind_string, password).await;
-        match bind {
-            Ok(ldapResult) => {
-                //RC 49 means invalid credentials
-                if ldapResult.rc == 49 {
-                    println!("Invalid credentials");
-                    return (false, String::from(""), vec![]);
-                } //RC 0 means success
-                else if ldapResult.rc == 0 {
-                    println!("Successfully bound to user");
-                } else {
-                    println!("Unknown error");
-                    return (false, String::from(""), vec![]);
-                }
-                println!("Successfully bound to user");
+        let found_groups = match self.find_groups(&mut ldap, &entry, &account).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                println!("Failed to get the groups of user {}: {}", username, e);
+                return AuthResult::failed("ldap_auth: ldap server error");
             }
+        };
+        //Lowercasing can make two groups the same
+        let mut groups: Vec<String> = vec![];
+        for group in found_groups {
+            let group = self.mapping.normalize(&group);
+            if !group.is_empty() && !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+
+        let result = match self.mapping.map(&entry, &account, groups) {
+            Ok(result) => result,
             Err(e) => {
-                println!("Failed to bind to user");
-                return (false, String::from(""), vec![]);
+                println!("Failed to map user {}: {}", username, e);
+                return AuthResult::denied();
             }
+        };
+
+        println!("Successfully authenticated user {} with ldap", result.username);
+
+        return result;
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading ldap_auth authenticator");
+
+        //Get the preset, default to none, active_directory sets the defaults for Active Directory
+        let active_directory = match env::var("LDAP_PRESET").unwrap_or("none".to_string()).as_str() {
+            "none" => false,
+            "active_directory" => true,
+            preset => {
+                println!("LDAP_PRESET {} is not valid, use none or active_directory", preset);
+                std::process::exit(1);
+            }
+        };
+
+        //Get ldap server urls, comma separated, default to ldap://localhost:3893
+        let ldap_server_urls: Vec<String> = env::var("LDAP_SERVER_URL")
+            .unwrap_or("ldap://localhost:3893".to_string())
+            .split(",")
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if ldap_server_urls.is_empty() {
+            println!("LDAP_SERVER_URL is empty");
+            std::process::exit(1);
         }
 
-        //bind string !format
-        let bind_string = format!("cn={},{}", &self.service_account_username, &self.base_dn);
+        //Get the failover between the servers, default to priority
+        let failover = match env::var("LDAP_FAILOVER").unwrap_or("priority".to_string()).as_str() {
+            "priority" => Failover::Priority,
+            "round_robin" => Failover::RoundRobin,
+            failover => {
+                println!("LDAP_FAILOVER {} is not valid, use priority or round_robin", failover);
+                std::process::exit(1);
+            }
+        };
 
-        let bind = ldap
-            .simple_bind(&bind_string, &self.service_account_password)
-            .await;
+        //Get service account username, default to admin, this is the username of the service account that is used to authenticate to the ldap server
+        //In glauth this is the serviceuser using the sample config
+        let service_account_username =
+            env::var("LDAP_SERVICE_ACCOUNT").unwrap_or("serviceuser".to_string());
 
-        //TODO: this check is not working, even if bind fails, it still returns Ok...
-        match bind {
-            Ok(ldapResult) => {
-                //RC 49 means invalid credentials
-                if ldapResult.rc == 49 {
-                    println!("Invalid credentials");
-                    return (false, String::from(""), vec![]);
-                } //RC 0 means success
-                else if ldapResult.rc == 0 {
-                    println!("Successfully bound to service account to get groups");
-                } else {
-                    println!("Unknown error");
-                    return (false, String::from(""), vec![]);
+        //Get service account password, default to mysecret, this is the password of the service account that is used to authenticate to the ldap server
+        //In glauth this is the serviceuser using the sample config
+        let service_account_password =
+            env::var("LDAP_SERVICE_ACCOUNT_PW").unwrap_or("mysecret".to_string());
+
+        //Get the base dn, default to dc=glauth,dc=com, this is the base dn that is used to search for users
+        //In glauth this is the dc=glauth,dc=com using the sample config
+        let base_dn = env::var("LDAP_BASE_DN").unwrap_or("dc=glauth,dc=com".to_string());
+
+        //Get the bind mode, default to template, search with the active_directory preset
+        let default_bind_mode = if active_directory { "search" } else { "template" };
+        let bind_mode = match env::var("LDAP_BIND_MODE").unwrap_or(default_bind_mode.to_string()).as_str() {
+            "template" => BindMode::Template,
+            "search" => BindMode::Search,
+            mode => {
+                println!("LDAP_BIND_MODE {} is not valid, use template or search", mode);
+                std::process::exit(1);
+            }
+        };
+
+        //Get the user dn template, default to cn=${username},${base_dn}, only used in template mode
+        let user_dn_template =
+            env::var("LDAP_USER_DN_TEMPLATE").unwrap_or("cn=${username},${base_dn}".to_string());
+
+        //Get the filter to find the user, default to cn=${username}, the sAMAccountName with the active_directory preset
+        //Example: (&(objectClass=person)(uid=${username}))
+        let default_filter = if active_directory {
+            "(&(objectCategory=person)(objectClass=user)(sAMAccountName=${username}))"
+        } else {
+            "cn=${username}"
+        };
+        let filter = env::var("LDAP_FILTER").unwrap_or(default_filter.to_string());
+
+        //Get the group mode, default to memberof, token_groups with the active_directory preset
+        let default_group_mode = if active_directory { "token_groups" } else { "memberof" };
+        let group_mode = match env::var("LDAP_GROUP_MODE").unwrap_or(default_group_mode.to_string()).as_str() {
+            "memberof" => GroupMode::MemberOf,
+            "search" => GroupMode::Search,
+            "ou" => GroupMode::Ou,
+            "token_groups" => GroupMode::TokenGroups,
+            mode => {
+                println!("LDAP_GROUP_MODE {} is not valid, use memberof, search, ou or token_groups", mode);
+                std::process::exit(1);
+            }
+        };
+
+        //Get the base dn of the group search, default to the base dn
+        let group_base_dn = env::var("LDAP_GROUP_BASE_DN").unwrap_or(base_dn.clone());
+
+        //Get the group filter, the default finds groupOfNames, groupOfUniqueNames and posixGroup groups
+        let group_filter = env::var("LDAP_GROUP_FILTER")
+            .unwrap_or("(|(member=${dn})(uniqueMember=${dn})(memberUid=${username}))".to_string());
+
+        //Get the attribute with the name of a group, default to cn
+        let group_name_attribute = env::var("LDAP_GROUP_NAME_ATTRIBUTE").unwrap_or("cn".to_string());
+
+        //Get the attributes of the username and uid, default to the login name
+        //The active_directory preset uses sAMAccountName and objectGUID, so all forms of the login are the same user
+        let (default_username_attribute, default_uid_attribute) = if active_directory {
+            ("sAMAccountName", "objectGUID")
+        } else {
+            ("", "")
+        };
+        let username_attribute = Some(env::var("LDAP_USERNAME_ATTRIBUTE").unwrap_or(default_username_attribute.to_string()))
+            .filter(|attribute| !attribute.is_empty());
+        let uid_attribute =
+            Some(env::var("LDAP_UID_ATTRIBUTE").unwrap_or(default_uid_attribute.to_string())).filter(|attribute| !attribute.is_empty());
+
+        //Get the extra attributes, comma separated key=attribute, e.g. email=mail,example.com/department=department
+        let mut extra_attributes: Vec<(String, String)> = vec![];
+        for mapping in env::var("LDAP_EXTRA_ATTRIBUTES").unwrap_or_default().split(",") {
+            if mapping.trim().is_empty() {
+                continue;
+            }
+            match mapping.split_once("=") {
+                Some((key, attribute)) if !key.trim().is_empty() && !attribute.trim().is_empty() => {
+                    extra_attributes.push((key.trim().to_string(), attribute.trim().to_string()))
+                }
+                _ => {
+                    println!("LDAP_EXTRA_ATTRIBUTES {} is not valid, use key=attribute", mapping);
+                    std::process::exit(1);
                 }
-                println!("Successfully bound to user");
             }
-            Err(e) => {
-                println!("Failed to bind to user");
-                return (false, String::from(""), vec![]);
+        }
+
+        //Get whether mapped values are lowercased and trimmed, default to false
+        let lowercase = env_bool("LDAP_LOWERCASE", false);
+        let trim = env_bool("LDAP_TRIM", false);
+
+        //Get how nested groups are resolved, default to none
+        let nested_groups = match env::var("LDAP_NESTED_GROUPS").unwrap_or("none".to_string()).as_str() {
+            "none" => NestedGroups::None,
+            "recursive" => NestedGroups::Recursive,
+            "in_chain" => NestedGroups::InChain,
+            nested => {
+                println!("LDAP_NESTED_GROUPS {} is not valid, use none, recursive or in_chain", nested);
+                std::process::exit(1);
             }
+        };
+        if group_mode == GroupMode::Ou && nested_groups != NestedGroups::None {
+            println!("LDAP_NESTED_GROUPS can't be used with LDAP_GROUP_MODE ou");
+            std::process::exit(1);
         }
+        if group_mode == GroupMode::TokenGroups && nested_groups != NestedGroups::None {
+            println!("LDAP_NESTED_GROUPS can't be used with LDAP_GROUP_MODE token_groups, tokenGroups contains the nested groups");
+            std::process::exit(1);
+        }
+
+        //Get the domains allowed in logins, comma separated, optional, e.g. CORP,corp.example.com
+        let ad_domains: Vec<String> = env::var("LDAP_AD_DOMAINS")
+            .unwrap_or_default()
+            .split(",")
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+
+        //Get the filter for user@domain logins, only used with the active_directory preset in search mode
+        let upn_filter = env::var("LDAP_UPN_FILTER")
+            .unwrap_or("(&(objectCategory=person)(objectClass=user)(userPrincipalName=${username}))".to_string());
+
+        //Get the pool size, default to 4, the number of service account connections and of concurrent user binds
+        let pool_size = env_number("LDAP_POOL_SIZE", 4);
+
+        //Get the timeout in seconds for connects and requests, default to 10
+        let timeout = Duration::from_secs(env_number("LDAP_TIMEOUT", 10));
 
-        //Build filter string, use usually not used symbols for variable replacement
-        //Example: (&(objectClass=person)(uid={}))
+        //Get the seconds after which idle connections are closed, default to 300
+        let idle_timeout = Duration::from_secs(env_number("LDAP_POOL_IDLE_TIMEOUT", 300));
+
+        //Get the tls settings of the connections
+        let settings = tls_settings(&ldap_server_urls);
+
+        //The service account is a DN, user@domain or DOMAIN\user, other names are cn=<name>,<base dn>
+        let service_dn = if service_account_username.contains(['=', '@', '\\']) {
+            service_account_username
+        } else {
+            format!("cn={},{}", service_account_username, base_dn)
+        };
+        let pool = LdapPool::new(
+            &ldap_server_urls,
+            failover,
+            settings,
+            &service_dn,
+            &service_account_password,
+            PoolLimits {
+                size: pool_size as usize,
+                timeout,
+                idle_timeout,
+            },
+        );
+
+        Self {
+            pool,
+            base_dn,
+            bind_mode,
+            user_dn_template,
+            filter,
+            group_mode,
+            group_base_dn,
+            group_filter,
+            group_name_attribute,
+            nested_groups,
+            mapping: UserMapping {
+                username_attribute,
+                uid_attribute,
+                extra_attributes,
+                lowercase,
+                trim,
+            },
+            active_directory,
+            ad_domains,
+            upn_filter,
+        }
+    }
+}
+
+impl LdapAuthenticator {
+    pub fn is_valid_username(username: &str) -> bool {
+        !username.is_empty() && username.len() <= 256 && !username.chars().any(|c| c.is_control())
+    }
+
+    //The account name of an Active Directory login and the userPrincipalName of user@domain logins
+    //None if the domain is not allowed
+    fn ad_account_name(&self, login: &str) -> Option<(String, Option<String>)> {
+        let (account, domain, upn) = match (login.split_once('\\'), login.rsplit_once('@')) {
+            (Some((domain, account)), _) => (account, Some(domain), None),
+            (None, Some((account, domain))) => (account, Some(domain), Some(login.to_string())),
+            (None, None) => (login, None, None),
+        };
+        if let Some(domain) = domain {
+            if !self.ad_domains.is_empty() && !self.ad_domains.contains(&domain.to_lowercase()) {
+                return None;
+            }
+        }
+        if account.is_empty() {
+            return None;
+        }
+        Some((account.to_string(), upn))
+    }
+
+    //Bind as the DN of the template, then read that entry with the service account
+    async fn template_bind(&self, ldap: &mut Ldap, username: &str, password: &str) -> Result<SearchEntry, LdapFailure> {
         let mut vars: HashMap<String, String> = HashMap::new();
-        vars.insert(String::from("username"), username.clone());
+        vars.insert(String::from("username"), username.to_string());
+        vars.insert(String::from("base_dn"), self.base_dn.clone());
+        let user_dn = replace_vars_escaped(&self.user_dn_template, &vars, dn_value);
+
+        //try to bind to user and check if it is successful, to check if username and password are correct
+        self.pool.check_password(&user_dn, password).await?;
+        println!("Successfully bound to user");
 
-        //Get filter from env
-        let filter = env::var("LDAP_FILTER").unwrap_or("cn=${username}".to_string());
-        let filter = replace_vars(&filter, &vars);
+        //The bound entry itself, a search with LDAP_FILTER could find another entry
+        match self.read_entry(ldap, &user_dn, self.user_attributes()).await.map_err(LdapFailure::Backend)? {
+            Some(entry) => Ok(entry),
+            None => Err(LdapFailure::Rejected(String::from("no entry found, failed to read user"))),
+        }
+    }
+
+    //Find the DN of the user with the service account, then bind as it
+    //user@domain logins of Active Directory are found by their userPrincipalName
+    async fn search_bind(&self, ldap: &mut Ldap, username: &str, upn: Option<&str>, password: &str) -> Result<SearchEntry, LdapFailure> {
+        let filter = self.user_filter(username, upn);
+        let mut entries = self.find_user(ldap, &filter).await.map_err(LdapFailure::Backend)?;
+        //A filter matching several users would let the password of any of them in
+        if entries.len() > 1 {
+            return Err(LdapFailure::Rejected(format!("filter matches {} entries", entries.len())));
+        }
+        let entry = match entries.pop() {
+            Some(entry) => entry,
+            None => return Err(LdapFailure::Rejected(String::from("no entry found, failed to find user"))),
+        };
+
+        self.pool.check_password(&entry.dn, password).await?;
+        println!("Successfully bound to user {}", entry.dn);
+        Ok(entry)
+    }
+
+    //The filter to find the user, by the userPrincipalName if there is one
+    fn user_filter(&self, username: &str, upn: Option<&str>) -> String {
+        let (filter, username) = match upn {
+            Some(upn) => (&self.upn_filter, upn),
+            None => (&self.filter, username),
+        };
 
-        //Search for cn=username
-        let mut search = ldap
-            .streaming_search(
+        //Build filter string, the username is escaped so it can't change the filter
+        let mut vars: HashMap<String, String> = HashMap::new();
+        vars.insert(String::from("username"), username.to_string());
+        replace_vars_escaped(filter, &vars, filter_value)
+    }
+
+    //Search the user with a filter
+    async fn find_user(&self, ldap: &mut Ldap, filter: &str) -> Result<Vec<SearchEntry>, String> {
+        let (entries, _) = ldap
+            .with_timeout(self.pool.timeout())
+            .search(
                 &self.base_dn, //example dc=example,dc=com
                 Scope::Subtree,
-                &filter,
-                vec!["l"],
+                filter,
+                self.user_attributes(),
             )
             .await
-            .unwrap();
+            .and_then(|result| result.success())
+            .map_err(|e| format!("error while searching for user: {}", e))?;
 
-        //Capture error
-        let entry = search.next().await;
-        //Completly unwrap entry but handle errors
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(e) => {
-                println!("Error while searching for user: {}", e);
-                return (false, String::from(""), vec![]);
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    //The attributes of the user that are needed
+    fn user_attributes(&self) -> Vec<&str> {
+        let mut attributes: Vec<&str> = vec![];
+        if self.group_mode == GroupMode::MemberOf {
+            attributes.push("memberOf");
+        }
+        attributes.extend(self.mapping.username_attribute.as_deref());
+        attributes.extend(self.mapping.uid_attribute.as_deref());
+        attributes.extend(self.mapping.extra_attributes.iter().map(|(_, attribute)| attribute.as_str()));
+        //No attributes
+        if attributes.is_empty() {
+            attributes.push("1.1");
+        }
+        attributes
+    }
+
+    //Get the names of the groups of the user
+    async fn find_groups(&self, ldap: &mut Ldap, entry: &SearchEntry, username: &str) -> Result<Vec<String>, String> {
+        if self.group_mode == GroupMode::Ou {
+            return Ok(LdapAuthenticator::ou_groups(&entry.dn));
+        }
+
+        //DN and name of the groups, the names are looked up later if they are not known yet
+        let mut groups: Vec<(String, Option<String>)> = vec![];
+
+        if self.group_mode == GroupMode::TokenGroups {
+            groups = self.token_groups(ldap, &entry.dn).await?;
+        } else if self.nested_groups == NestedGroups::InChain {
+            //The server returns the direct and the nested groups at once
+            let filter = format!("(member:{}:={})", MATCHING_RULE_IN_CHAIN, ldap_escape(&entry.dn));
+            groups = self.search_groups(ldap, &filter).await?;
+        } else {
+            let mut pending = match self.group_mode {
+                GroupMode::Search => self.search_groups(ldap, &self.group_filter(&entry.dn, username)).await?,
+                _ => attribute_values(entry, "memberOf").into_iter().map(|dn| (dn, None)).collect(),
+            };
+
+            let mut depth = 0;
+            while !pending.is_empty() {
+                let mut parents: Vec<(String, Option<String>)> = vec![];
+                for group in pending {
+                    if groups.iter().any(|(dn, _)| dn.eq_ignore_ascii_case(&group.0)) {
+                        continue;
+                    }
+                    if self.nested_groups == NestedGroups::Recursive && depth < MAX_GROUP_DEPTH {
+                        parents.extend(self.parent_groups(ldap, &group.0).await?);
+                    }
+                    groups.push(group);
+                }
+                pending = parents;
+                depth += 1;
             }
-        };
+        }
 
-        
-        let entry = match entry {
-            Some(entry) => entry,
-            None => {
-                println!("No entry found, failed to find user");
-                return (false, String::from(""), vec![]);
+        let mut names: Vec<String> = vec![];
+        for (dn, name) in groups {
+            let name = match name {
+                Some(name) => Some(name),
+                None => self.group_name(ldap, &dn).await?,
+            };
+            match name {
+                Some(name) if !names.contains(&name) => names.push(name),
+                _ => {}
             }
+        }
+        Ok(names)
+    }
+
+    //The group filter for a member
+    fn group_filter(&self, dn: &str, username: &str) -> String {
+        let mut vars: HashMap<String, String> = HashMap::new();
+        vars.insert(String::from("dn"), dn.to_string());
+        vars.insert(String::from("username"), username.to_string());
+        replace_vars_escaped(&self.group_filter, &vars, filter_value)
+    }
+
+    //Search groups, returns their DN and name
+    async fn search_groups(&self, ldap: &mut Ldap, filter: &str) -> Result<Vec<(String, Option<String>)>, String> {
+        let (entries, _) = ldap
+            .with_timeout(self.pool.timeout())
+            .search(&self.group_base_dn, Scope::Subtree, filter, vec![self.group_name_attribute.as_str()])
+            .await
+            .and_then(|result| result.success())
+            .map_err(|e| format!("error while searching for groups: {}", e))?;
+
+        Ok(entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .map(|entry| {
+                let name = attribute_values(&entry, &self.group_name_attribute).into_iter().next();
+                (entry.dn, name)
+            })
+            .collect())
+    }
+
+    //The groups of the SIDs in tokenGroups, Active Directory only returns tokenGroups if the user is read by its DN
+    async fn token_groups(&self, ldap: &mut Ldap, dn: &str) -> Result<Vec<(String, Option<String>)>, String> {
+        let sids = match self.read_entry(ldap, dn, vec!["tokenGroups"]).await? {
+            Some(entry) => attribute_bytes(&entry, "tokenGroups"),
+            None => return Ok(vec![]),
         };
 
-        //Convert entry to SearchEntry
-        let entry = SearchEntry::construct(entry);
+        //Search the groups by objectSid, some at a time so the filters stay small
+        let mut groups: Vec<(String, Option<String>)> = vec![];
+        for sids in sids.chunks(50) {
+            let filter: String = sids.iter().map(|sid| format!("(objectSid={})", escape_bytes(sid))).collect();
+            groups.extend(self.search_groups(ldap, &format!("(|{})", filter)).await?);
+        }
+        Ok(groups)
+    }
+
+    //The groups a group is a member of
+    async fn parent_groups(&self, ldap: &mut Ldap, dn: &str) -> Result<Vec<(String, Option<String>)>, String> {
+        match self.group_mode {
+            //posixGroups have no members that are groups, memberUid can't match a group
+            GroupMode::Search => self.search_groups(ldap, &self.group_filter(dn, "")).await,
+            _ => Ok(self
+                .read_attribute(ldap, dn, "memberOf")
+                .await?
+                .into_iter()
+                .map(|dn| (dn, None))
+                .collect()),
+        }
+    }
 
-        //println!("entry: {:?}", entry);
-        //Get ou from entry.dn
+    //The name of a group, taken from its DN if the name attribute is the first part of it
+    async fn group_name(&self, ldap: &mut Ldap, dn: &str) -> Result<Option<String>, String> {
+        if let Some((attribute, value)) = first_rdn(dn) {
+            if attribute.eq_ignore_ascii_case(&self.group_name_attribute) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(self.read_attribute(ldap, dn, &self.group_name_attribute).await?.into_iter().next())
+    }
+
+    //Read an attribute of an entry, a missing entry has no values
+    async fn read_attribute(&self, ldap: &mut Ldap, dn: &str, attribute: &str) -> Result<Vec<String>, String> {
+        Ok(self
+            .read_entry(ldap, dn, vec![attribute])
+            .await?
+            .map(|entry| attribute_values(&entry, attribute))
+            .unwrap_or_default())
+    }
 
+    //Read an entry by its DN with the given attributes, None if it doesn't exist
+    async fn read_entry(&self, ldap: &mut Ldap, dn: &str, attributes: Vec<&str>) -> Result<Option<SearchEntry>, String> {
+        let attribute = attributes.join(", ");
+        let result = ldap
+            .with_timeout(self.pool.timeout())
+            .search(dn, Scope::Base, "(objectClass=*)", attributes)
+            .await
+            .map_err(|e| format!("error while reading {} of {}: {}", attribute, dn, e))?;
+        //32 means no such object
+        if result.1.rc == 32 {
+            return Ok(None);
+        }
+        let (entries, _) = result
+            .success()
+            .map_err(|e| format!("error while reading {} of {}: {}", attribute, dn, e))?;
+
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    //The ou= parts of a DN
+    fn ou_groups(dn: &str) -> Vec<String> {
         //Split by comma
-        let entry_vals: Vec<&str> = entry.dn.split(",").collect();
+        let entry_vals: Vec<&str> = dn.split(",").collect();
 
         //Each val of entry vals is a key value pair, split by =
         //Search for all ou= values and save them to a vec
@@ -183,51 +725,416 @@ impl Authenticator for LdapAuthenticator {
                 groups.push(val[1].to_string());
             }
         }
-        //Print groups
-        //println!("groups: {:?}", groups);
-
-        print!("Successfully authenticated user {} with ldap", username);
+        groups
+    }
+}
 
-        return (true, String::from(username), groups);
+//Build the connection settings from LDAP_STARTTLS and the LDAP_TLS_* variables, exits if they are not valid
+fn tls_settings(urls: &[String]) -> LdapConnSettings {
+    //Get starttls, default to false, upgrades ldap:// connections to tls before the bind
+    let starttls = env_bool("LDAP_STARTTLS", false);
+    for url in urls {
+        let ldaps = url.starts_with("ldaps://");
+        if starttls && ldaps {
+            println!("LDAP_STARTTLS can't be used with ldaps:// urls, they are already using tls");
+            std::process::exit(1);
+        }
+        if !starttls && !ldaps {
+            println!("WARNING: ldap_auth connects to {} without tls, passwords of users and of the service account are sent in plaintext", url);
+        }
+    }
+    if !starttls && urls.iter().any(|url| !url.starts_with("ldaps://")) {
+        println!("WARNING: use ldaps:// urls or LDAP_STARTTLS=true");
     }
 
-    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
-        println!("Loading ldap_auth authenticator");
+    let mut builder = TlsConnector::builder();
 
-        //Get ldap server url, default to ldap://localhost:389
-        let ldap_server_url =
-            env::var("LDAP_SERVER_URL").unwrap_or("ldap://localhost:3893".to_string());
+    //Get the minimum tls version, default to 1.2
+    let min_version = match env::var("LDAP_TLS_MIN_VERSION").unwrap_or("1.2".to_string()).as_str() {
+        "1.0" => Protocol::Tlsv10,
+        "1.1" => Protocol::Tlsv11,
+        "1.2" => Protocol::Tlsv12,
+        version => {
+            println!("LDAP_TLS_MIN_VERSION {} is not valid, use 1.0, 1.1 or 1.2", version);
+            std::process::exit(1);
+        }
+    };
+    builder.min_protocol_version(Some(min_version));
 
-        //Get service account username, default to admin, this is the username of the service account that is used to authenticate to the ldap server
-        //In glauth this is the serviceuser using the sample config
-        let service_account_username =
-            env::var("LDAP_SERVICE_ACCOUNT").unwrap_or("serviceuser".to_string());
+    //Get the ca bundle, optional, only these CAs are trusted if it is set
+    if let Ok(path) = env::var("LDAP_CA_FILE") {
+        let certificates = match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|pem| pem_certificates(&pem)) {
+            Ok(certificates) if !certificates.is_empty() => certificates,
+            Ok(_) => {
+                println!("LDAP_CA_FILE {} contains no certificates", path);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                println!("LDAP_CA_FILE {} is not valid: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        builder.disable_built_in_roots(true);
+        for certificate in certificates {
+            builder.add_root_certificate(certificate);
+        }
+    }
 
-        //Get service account password, default to mysecret, this is the password of the service account that is used to authenticate to the ldap server
-        //In glauth this is the serviceuser using the sample config
-        let service_account_password =
-            env::var("LDAP_SERVICE_ACCOUNT_PW").unwrap_or("mysecret".to_string());
+    //Get the client certificate and its PKCS#8 key, optional
+    match (env::var("LDAP_CLIENT_CERT_FILE"), env::var("LDAP_CLIENT_KEY_FILE")) {
+        (Ok(cert_path), Ok(key_path)) => {
+            let identity = match (fs::read(&cert_path), fs::read(&key_path)) {
+                (Ok(cert), Ok(key)) => Identity::from_pkcs8(&cert, &key).map_err(|e| e.to_string()),
+                (Err(e), _) | (_, Err(e)) => Err(e.to_string()),
+            };
+            match identity {
+                Ok(identity) => {
+                    builder.identity(identity);
+                }
+                Err(e) => {
+                    println!("LDAP_CLIENT_CERT_FILE {} or LDAP_CLIENT_KEY_FILE {} is not valid: {}", cert_path, key_path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (Err(_), Err(_)) => {}
+        _ => {
+            println!("LDAP_CLIENT_CERT_FILE and LDAP_CLIENT_KEY_FILE must be set together");
+            std::process::exit(1);
+        }
+    }
 
-        //Get the base dn, default to dc=glauth,dc=com, this is the base dn that is used to search for users
-        //In glauth this is the dc=glauth,dc=com using the sample config
-        let base_dn = env::var("LDAP_BASE_DN").unwrap_or("dc=glauth,dc=com".to_string());
+    //Get hostname verification, default to true
+    if !env_bool("LDAP_TLS_VERIFY_HOSTNAME", true) {
+        println!("WARNING: ldap_auth doesn't verify the hostnames of the ldap servers");
+        builder.danger_accept_invalid_hostnames(true);
+    }
 
-        Self {
-            ldap_server_url,
-            service_account_username,
-            service_account_password,
-            base_dn,
+    let connector = match builder.build() {
+        Ok(connector) => connector,
+        Err(e) => {
+            println!("Failed to create the tls settings of ldap_auth: {}", e);
+            std::process::exit(1);
+        }
+    };
+    LdapConnSettings::new().set_connector(connector).set_starttls(starttls)
+}
+
+//The certificates of a PEM bundle
+fn pem_certificates(pem: &str) -> Result<Vec<Certificate>, String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    pem.split_inclusive("-----END CERTIFICATE-----")
+        .filter_map(|block| block.find(BEGIN).map(|start| &block[start..]))
+        .map(|block| Certificate::from_pem(block.as_bytes()).map_err(|e| e.to_string()))
+        .collect()
+}
+
+//Read true or false from an environment variable, exits if it is something else
+fn env_bool(name: &str, default: bool) -> bool {
+    match env::var(name).as_deref() {
+        Ok("true") => true,
+        Ok("false") => false,
+        Ok(_) => {
+            println!("{} must be true or false", name);
+            std::process::exit(1);
         }
+        Err(_) => default,
     }
 }
 
-impl LdapAuthenticator {
-    pub fn contains_only_alphanumeric(username: String) -> bool {
-        for c in username.chars() {
-            if !c.is_alphanumeric() {
-                return false;
+//Read a positive number from an environment variable, exits if it is not valid
+fn env_number(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(number) if number > 0 => number,
+            _ => {
+                println!("{} is not a valid number", name);
+                std::process::exit(1);
             }
+        },
+        Err(_) => default,
+    }
+}
+
+//Escape a variable for a filter (RFC 4515), so * ( ) and \ are matched literally
+fn filter_value(_name: &str, value: &str) -> String {
+    ldap_escape(value).into_owned()
+}
+
+//Escape a variable for a DN (RFC 4514), so , + = and \ can't add parts to the DN
+//base_dn is already a DN and is kept as it is
+fn dn_value(name: &str, value: &str) -> String {
+    match name {
+        "base_dn" => value.to_string(),
+        _ => dn_escape(value).into_owned(),
+    }
+}
+
+//The values of an attribute, attribute names are case insensitive
+fn attribute_values(entry: &SearchEntry, attribute: &str) -> Vec<String> {
+    entry
+        .attrs
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(attribute))
+        .flat_map(|(_, values)| values.clone())
+        .collect()
+}
+
+//The values of a binary attribute, e.g. objectGUID
+//SearchEntry keeps values that are valid UTF-8 in attrs, so both are read
+fn attribute_bytes(entry: &SearchEntry, attribute: &str) -> Vec<Vec<u8>> {
+    let strings = entry
+        .attrs
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(attribute))
+        .flat_map(|(_, values)| values.iter().map(|value| value.as_bytes().to_vec()));
+    let binaries = entry
+        .bin_attrs
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(attribute))
+        .flat_map(|(_, values)| values.clone());
+    strings.chain(binaries).collect()
+}
+
+//Escape binary data for a filter, every byte as \xx
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:02x}", b)).collect()
+}
+
+//Format an objectGUID like Active Directory does, the first three parts are little endian
+//e.g. 3f2504e0-4f89-11d3-9a0c-0305e82c3301
+fn format_guid(bytes: &[u8]) -> Option<String> {
+    let b: &[u8; 16] = bytes.try_into().ok()?;
+    Some(format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    ))
+}
+
+//The attribute and unescaped value of the first part of a DN, e.g. (cn, admins) of cn=admins,ou=groups,dc=example,dc=com
+fn first_rdn(dn: &str) -> Option<(String, String)> {
+    let (attribute, rest) = dn.split_once("=")?;
+    let mut value: Vec<u8> = vec![];
+    let mut bytes = rest.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => {
+                let next = bytes.next()?;
+                //Either an escaped character or two hex digits
+                if next.is_ascii_hexdigit() {
+                    let low = bytes.next()?;
+                    let hex = [next, low];
+                    value.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                } else {
+                    value.push(next);
+                }
+            }
+            //Multi valued RDNs end at + too
+            b',' | b'+' => break,
+            b => value.push(b),
+        }
+    }
+    Some((attribute.trim().to_string(), String::from_utf8(value).ok()?.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Usernames that try to change the filter or the DN, and ones that need no escaping
+    const USERNAMES: &[&str] = &["*", "*)(uid=*", "a\\29", "a\0b", ",ou=admins", "+cn=x", "first.last", "user@corp.example"];
+
+    //An authenticator with the defaults of the active_directory preset, the pool never connects in these tests
+    fn active_directory(ad_domains: &[&str]) -> LdapAuthenticator {
+        LdapAuthenticator {
+            pool: LdapPool::new(
+                &[String::from("ldap://127.0.0.1:1")],
+                Failover::Priority,
+                LdapConnSettings::new(),
+                "cn=svc,dc=corp,dc=example",
+                "svcpw",
+                PoolLimits {
+                    size: 1,
+                    timeout: Duration::from_secs(1),
+                    idle_timeout: Duration::from_secs(1),
+                },
+            ),
+            base_dn: String::from("dc=corp,dc=example"),
+            bind_mode: BindMode::Search,
+            user_dn_template: String::from("cn=${username},${base_dn}"),
+            filter: String::from("(&(objectCategory=person)(objectClass=user)(sAMAccountName=${username}))"),
+            group_mode: GroupMode::TokenGroups,
+            group_base_dn: String::from("dc=corp,dc=example"),
+            group_filter: String::from("(|(member=${dn})(uniqueMember=${dn})(memberUid=${username}))"),
+            group_name_attribute: String::from("cn"),
+            nested_groups: NestedGroups::None,
+            mapping: UserMapping {
+                username_attribute: Some(String::from("sAMAccountName")),
+                uid_attribute: Some(String::from("objectGUID")),
+                extra_attributes: vec![],
+                lowercase: false,
+                trim: false,
+            },
+            active_directory: true,
+            ad_domains: ad_domains.iter().map(|d| d.to_string()).collect(),
+            upn_filter: String::from("(&(objectCategory=person)(objectClass=user)(userPrincipalName=${username}))"),
+        }
+    }
+
+    //The filter a login is searched with, None if the login is denied
+    fn login_filter(ldap: &LdapAuthenticator, login: &str) -> Option<String> {
+        let (account, upn) = ldap.ad_account_name(login)?;
+        Some(ldap.user_filter(&account, upn.as_deref()))
+    }
+
+    #[test]
+    fn active_directory_logins_are_found_by_upn_or_account_name() {
+        let ldap = active_directory(&[]);
+        let sam = |account: &str| format!("(&(objectCategory=person)(objectClass=user)(sAMAccountName={}))", account);
+        let upn = |upn: &str| format!("(&(objectCategory=person)(objectClass=user)(userPrincipalName={}))", upn);
+
+        assert_eq!(login_filter(&ldap, "jdoe"), Some(sam("jdoe")));
+        assert_eq!(login_filter(&ldap, "CORP\\jdoe"), Some(sam("jdoe")));
+        //The UPN may differ from the account name, so user@domain is searched as it is
+        assert_eq!(login_filter(&ldap, "john.smith@corp.example"), Some(upn("john.smith@corp.example")));
+        //Only the last @ separates the domain
+        assert_eq!(login_filter(&ldap, "a@b@corp.example"), Some(upn("a@b@corp.example")));
+        //DOMAIN\user wins over @, the account part is escaped
+        assert_eq!(login_filter(&ldap, "CORP\\a*@x"), Some(sam("a\\2a@x")));
+        assert_eq!(login_filter(&ldap, "*)(cn=*@corp.example"), Some(upn("\\2a\\29\\28cn=\\2a@corp.example")));
+
+        assert_eq!(login_filter(&ldap, "CORP\\"), None);
+        assert_eq!(login_filter(&ldap, "@corp.example"), None);
+    }
+
+    #[test]
+    fn active_directory_domains_can_be_restricted() {
+        let ldap = active_directory(&["corp", "corp.example"]);
+
+        assert!(login_filter(&ldap, "jdoe").is_some());
+        assert!(login_filter(&ldap, "CORP\\jdoe").is_some());
+        assert!(login_filter(&ldap, "jdoe@Corp.Example").is_some());
+        assert_eq!(login_filter(&ldap, "OTHER\\jdoe"), None);
+        assert_eq!(login_filter(&ldap, "jdoe@other.example"), None);
+    }
+
+    fn mapping(username_attribute: Option<&str>, uid_attribute: Option<&str>, lowercase: bool, trim: bool) -> UserMapping {
+        UserMapping {
+            username_attribute: username_attribute.map(String::from),
+            uid_attribute: uid_attribute.map(String::from),
+            extra_attributes: vec![(String::from("mail"), String::from("mail"))],
+            lowercase,
+            trim,
+        }
+    }
+
+    fn entry(attrs: &[(&str, &[&str])], bin_attrs: &[(&str, Vec<u8>)]) -> SearchEntry {
+        SearchEntry {
+            dn: String::from("cn=jdoe,dc=corp,dc=example"),
+            attrs: attrs.iter().map(|(name, values)| (name.to_string(), values.iter().map(|v| v.to_string()).collect())).collect(),
+            bin_attrs: bin_attrs.iter().map(|(name, value)| (name.to_string(), vec![value.clone()])).collect(),
+        }
+    }
+
+    #[test]
+    fn object_guids_are_formatted_like_active_directory() {
+        let guid: Vec<u8> = vec![0xe0, 0x04, 0x25, 0x3f, 0x89, 0x4f, 0xd3, 0x11, 0x9a, 0x0c, 0x03, 0x05, 0xe8, 0x2c, 0x33, 0x01];
+        let entry = entry(&[("sAMAccountName", &["jdoe"])], &[("objectGUID", guid.clone())]);
+
+        let result = mapping(Some("sAMAccountName"), Some("objectGUID"), false, false).map(&entry, "jdoe", vec![]).unwrap();
+        assert_eq!(result.uid, "3f2504e0-4f89-11d3-9a0c-0305e82c3301");
+        //The attribute name is matched without case
+        let result = mapping(Some("samaccountname"), Some("objectguid"), false, false).map(&entry, "jdoe", vec![]).unwrap();
+        assert_eq!(result.uid, "3f2504e0-4f89-11d3-9a0c-0305e82c3301");
+
+        //A value that isn't 16 bytes is not a GUID
+        let entry = self::entry(&[("sAMAccountName", &["jdoe"])], &[("objectGUID", guid[..15].to_vec())]);
+        assert!(mapping(Some("sAMAccountName"), Some("objectGUID"), false, false).map(&entry, "jdoe", vec![]).is_err());
+    }
+
+    #[test]
+    fn missing_attributes_are_errors_except_for_extra() {
+        let entry = entry(&[("uid", &["jdoe"]), ("empty", &[" "])], &[]);
+
+        assert!(mapping(Some("cn"), None, false, false).map(&entry, "jdoe", vec![]).is_err());
+        assert!(mapping(Some("uid"), Some("uidNumber"), false, false).map(&entry, "jdoe", vec![]).is_err());
+        assert!(mapping(Some("uid"), Some("objectGUID"), false, false).map(&entry, "jdoe", vec![]).is_err());
+        //Empty after trimming is missing
+        assert!(mapping(Some("empty"), None, false, true).map(&entry, "jdoe", vec![]).is_err());
+
+        let result = mapping(Some("uid"), None, false, false).map(&entry, "jdoe", vec![]).unwrap();
+        assert_eq!((result.username.as_str(), result.uid.as_str()), ("jdoe", "jdoe"));
+        assert!(result.extra.is_empty());
+    }
+
+    #[test]
+    fn multi_valued_attributes_use_the_first_value_or_all_for_extra() {
+        let entry = entry(&[("uid", &["jdoe", "john"]), ("mail", &["jdoe@corp.example", "", "John.Doe@corp.example"])], &[]);
+
+        let result = mapping(Some("uid"), Some("uid"), true, false).map(&entry, "jdoe", vec![]).unwrap();
+        assert_eq!((result.username.as_str(), result.uid.as_str()), ("jdoe", "jdoe"));
+        //Empty values are left out
+        assert_eq!(result.extra["mail"], vec!["jdoe@corp.example", "john.doe@corp.example"]);
+    }
+
+    #[test]
+    fn the_login_name_is_normalized_without_a_username_attribute() {
+        let entry = entry(&[("uid", &[" JDoe "]), ("mail", &[" JDoe@Corp.Example "])], &[]);
+
+        let result = mapping(None, None, true, true).map(&entry, " JDoe ", vec![]).unwrap();
+        assert_eq!((result.username.as_str(), result.uid.as_str()), ("jdoe", "jdoe"));
+        assert_eq!(result.extra["mail"], vec!["jdoe@corp.example"]);
+
+        //Without lowercase and trim the login name is used as it is
+        let result = mapping(None, None, false, false).map(&entry, " JDoe ", vec![]).unwrap();
+        assert_eq!(result.username, " JDoe ");
+        assert_eq!(result.extra["mail"], vec![" JDoe@Corp.Example "]);
+
+        //The uid attribute is trimmed but never lowercased
+        let result = mapping(Some("uid"), Some("uid"), true, true).map(&entry, "other", vec![]).unwrap();
+        assert_eq!((result.username.as_str(), result.uid.as_str()), ("jdoe", "JDoe"));
+    }
+
+    fn vars(username: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("username"), username.to_string()),
+            (String::from("base_dn"), String::from("dc=example,dc=com")),
+        ])
+    }
+
+    #[test]
+    fn usernames_are_escaped_in_filters() {
+        let expected = [
+            "(&(objectClass=person)(uid=\\2a))",
+            "(&(objectClass=person)(uid=\\2a\\29\\28uid=\\2a))",
+            "(&(objectClass=person)(uid=a\\5c29))",
+            "(&(objectClass=person)(uid=a\\00b))",
+            "(&(objectClass=person)(uid=,ou=admins))",
+            "(&(objectClass=person)(uid=+cn=x))",
+            "(&(objectClass=person)(uid=first.last))",
+            "(&(objectClass=person)(uid=user@corp.example))",
+        ];
+        for (username, expected) in USERNAMES.iter().zip(expected) {
+            let filter = replace_vars_escaped("(&(objectClass=person)(uid=${username}))", &vars(username), filter_value);
+            assert_eq!(filter, expected, "{:?}", username);
+        }
+    }
+
+    #[test]
+    fn usernames_are_escaped_in_dns() {
+        let expected = [
+            "uid=*,ou=people,dc=example,dc=com",
+            "uid=*)(uid\\3d*,ou=people,dc=example,dc=com",
+            "uid=a\\5c29,ou=people,dc=example,dc=com",
+            "uid=a\\00b,ou=people,dc=example,dc=com",
+            "uid=\\2cou\\3dadmins,ou=people,dc=example,dc=com",
+            "uid=\\2bcn\\3dx,ou=people,dc=example,dc=com",
+            "uid=first.last,ou=people,dc=example,dc=com",
+            "uid=user@corp.example,ou=people,dc=example,dc=com",
+        ];
+        for (username, expected) in USERNAMES.iter().zip(expected) {
+            let dn = replace_vars_escaped("uid=${username},ou=people,${base_dn}", &vars(username), dn_value);
+            assert_eq!(dn, expected, "{:?}", username);
         }
-        true
     }
 }
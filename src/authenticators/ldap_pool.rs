@@ -0,0 +1,626 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ldap3::{exop::WhoAmI, Ldap, LdapConnAsync, LdapConnSettings, LdapError};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/*
+Connections to the ldap server for ldap_auth.
+Searches use a pool of connections bound as the service account, they are kept open and reused.
+Passwords are checked on short-lived connections that are closed after the bind, so a user bind
+never changes the identity of a pooled connection.
+Idle connections are closed after the idle timeout and checked with a whoami request before
+they are reused after a while.
+There can be several servers, new connections go to the first available one (priority) or
+to the next one in turn (round robin). After a failed connect the circuit of a server is open,
+it is skipped until a backoff has passed that doubles with every failure. Then one connect
+tries the server again, so a dead server costs at most one connect timeout per backoff.
+A service account bind the server can't answer opens the circuit too. A user bind never does and
+is not repeated on another server, so a password counts only once against the lockout of the user.
+*/
+
+//Idle connections unused for this long are checked before they are reused
+const HEALTH_CHECK_AFTER: Duration = Duration::from_secs(30);
+
+//Backoff after the first failed connect, doubled per failure up to MAX_BACKOFF
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+//Which server new connections go to
+#[derive(PartialEq)]
+pub enum Failover {
+    //The first available server in the order of the list
+    Priority,
+    //The next available server after the one used last
+    RoundRobin,
+}
+
+//Why a ldap operation failed
+pub enum LdapFailure {
+    //The server rejected the user, e.g. a wrong password or an unknown user
+    Rejected(String),
+    //The servers could not be reached or returned an error
+    Backend(String),
+}
+
+impl fmt::Display for LdapFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LdapFailure::Rejected(e) | LdapFailure::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+//Size and timeouts of the pool
+pub struct PoolLimits {
+    //The number of service connections and of concurrent user binds
+    pub size: usize,
+    //Used for connects, operations and waiting for a free connection
+    pub timeout: Duration,
+    //Unused connections are closed after this
+    pub idle_timeout: Duration,
+}
+
+struct IdleConnection {
+    ldap: Ldap,
+    idle_since: Instant,
+}
+
+//Health of a server, the circuit is open while retry_at is in the future
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+struct Server {
+    url: String,
+    health: Mutex<Health>,
+}
+
+impl Server {
+    //Whether a connect may be tried, claims the retry after an open circuit so only one connect tries the server
+    fn try_connect(&self, timeout: Duration) -> bool {
+        let mut health = self.health.lock().unwrap();
+        match health.retry_at {
+            Some(retry_at) if Instant::now() < retry_at => false,
+            Some(_) => {
+                health.retry_at = Some(Instant::now() + timeout);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn connected(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.failures > 0 {
+            println!("ldap server {} is available again", self.url);
+        }
+        *health = Health::default();
+    }
+
+    fn failed(&self) {
+        let mut health = self.health.lock().unwrap();
+        let delay = MIN_BACKOFF
+            .saturating_mul(2u32.saturating_pow(health.failures))
+            .min(MAX_BACKOFF);
+        health.failures += 1;
+        health.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+pub struct LdapPool {
+    servers: Vec<Server>,
+    failover: Failover,
+    //The server of the last round robin connect
+    next_server: AtomicUsize,
+    settings: LdapConnSettings,
+    timeout: Duration,
+    idle_timeout: Duration,
+    service_dn: String,
+    service_password: String,
+    idle: Mutex<Vec<IdleConnection>>,
+    //Limits the service connections and the user bind connections
+    service_slots: Semaphore,
+    bind_slots: Semaphore,
+}
+
+//A service account connection, returned to the pool when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a LdapPool,
+    ldap: Ldap,
+    _slot: SemaphorePermit<'a>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Ldap;
+
+    fn deref(&self) -> &Ldap {
+        &self.ldap
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Ldap {
+        &mut self.ldap
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if !self.ldap.is_closed() {
+            self.pool.idle.lock().unwrap().push(IdleConnection {
+                ldap: self.ldap.clone(),
+                idle_since: Instant::now(),
+            });
+        }
+    }
+}
+
+impl LdapPool {
+    pub fn new(
+        urls: &[String],
+        failover: Failover,
+        settings: LdapConnSettings,
+        service_dn: &str,
+        service_password: &str,
+        limits: PoolLimits,
+    ) -> Self {
+        LdapPool {
+            servers: urls
+                .iter()
+                .map(|url| Server {
+                    url: url.clone(),
+                    health: Mutex::new(Health::default()),
+                })
+                .collect(),
+            failover,
+            next_server: AtomicUsize::new(0),
+            settings: settings.set_conn_timeout(limits.timeout),
+            timeout: limits.timeout,
+            idle_timeout: limits.idle_timeout,
+            service_dn: service_dn.to_string(),
+            service_password: service_password.to_string(),
+            idle: Mutex::new(vec![]),
+            service_slots: Semaphore::new(limits.size),
+            bind_slots: Semaphore::new(limits.size),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    //Get a connection bound as the service account, reuses an idle connection if there is a healthy one
+    pub async fn service_connection(&self) -> Result<PooledConnection<'_>, String> {
+        let slot = self.wait_for_slot(&self.service_slots).await?;
+
+        while let Some(mut idle) = self.take_idle() {
+            if idle.idle_since.elapsed() >= HEALTH_CHECK_AFTER && !self.is_healthy(&mut idle.ldap).await {
+                continue;
+            }
+            return Ok(PooledConnection {
+                pool: self,
+                ldap: idle.ldap,
+                _slot: slot,
+            });
+        }
+
+        let ldap = self
+            .connect(&self.service_dn, &self.service_password, false)
+            .await
+            .map_err(|e| match e {
+                LdapFailure::Rejected(e) => format!("service account: {}", e),
+                LdapFailure::Backend(e) => e,
+            })?;
+        Ok(PooledConnection {
+            pool: self,
+            ldap,
+            _slot: slot,
+        })
+    }
+
+    //Check the password of a user with a bind on a new connection, the connection is closed afterwards
+    pub async fn check_password(&self, dn: &str, password: &str) -> Result<(), LdapFailure> {
+        let _slot = self.wait_for_slot(&self.bind_slots).await.map_err(LdapFailure::Backend)?;
+        let mut ldap = self.connect(dn, password, true).await?;
+        let _ = ldap.with_timeout(self.timeout).unbind().await;
+        Ok(())
+    }
+
+    async fn wait_for_slot<'a>(&self, slots: &'a Semaphore) -> Result<SemaphorePermit<'a>, String> {
+        match tokio::time::timeout(self.timeout, slots.acquire()).await {
+            Ok(Ok(slot)) => Ok(slot),
+            Ok(Err(e)) => Err(format!("ldap pool is closed: {}", e)),
+            Err(_) => Err(String::from("timed out waiting for a free ldap connection")),
+        }
+    }
+
+    //Take the most recently used idle connection, closes connections that are idle for too long
+    fn take_idle(&self) -> Option<IdleConnection> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain_mut(|c| c.idle_since.elapsed() < self.idle_timeout && !c.ldap.is_closed());
+        idle.pop()
+    }
+
+    async fn is_healthy(&self, ldap: &mut Ldap) -> bool {
+        ldap.with_timeout(self.timeout)
+            .extended(WhoAmI)
+            .await
+            .and_then(|result| result.success())
+            .is_ok()
+    }
+
+    //The servers in the order connects try them
+    fn server_order(&self) -> Vec<&Server> {
+        let first = match self.failover {
+            Failover::Priority => 0,
+            Failover::RoundRobin => self.next_server.fetch_add(1, Ordering::Relaxed),
+        };
+        (0..self.servers.len())
+            .map(|i| &self.servers[(first + i) % self.servers.len()])
+            .collect()
+    }
+
+    //Open a connection bound as dn to the first server that is available, in the order of the failover
+    //Servers with an open circuit are skipped, so a dead server doesn't slow down every connect
+    //A server that fails to connect or to bind the service account is marked as failed and the next one is tried,
+    //a bind rejected by the server and every failed user bind are returned right away
+    async fn connect(&self, dn: &str, password: &str, user_bind: bool) -> Result<Ldap, LdapFailure> {
+        let mut errors: Vec<String> = vec![];
+        for server in self.server_order() {
+            if !server.try_connect(self.timeout) {
+                errors.push(format!("{} is unavailable", server.url));
+                continue;
+            }
+
+            let mut ldap = match LdapConnAsync::with_settings(self.settings.clone(), &server.url).await {
+                Ok((conn, ldap)) => {
+                    ldap3::drive!(conn);
+                    ldap
+                }
+                Err(e) => {
+                    println!("Failed to connect to ldap server {}: {}", server.url, e);
+                    server.failed();
+                    errors.push(format!("{}: {}", server.url, e));
+                    continue;
+                }
+            };
+
+            match bind(&mut ldap, self.timeout, dn, password).await {
+                //The server may have checked the password anyway, another try on the next server could lock the user out
+                Err(LdapFailure::Backend(e)) if user_bind => {
+                    println!("Failed to bind user to ldap server {}: {}", server.url, e);
+                    server.connected();
+                    return Err(LdapFailure::Backend(format!("{}: {}", server.url, e)));
+                }
+                Err(LdapFailure::Backend(e)) => {
+                    println!("Failed to bind to ldap server {}: {}", server.url, e);
+                    server.failed();
+                    errors.push(format!("{}: {}", server.url, e));
+                }
+                result => {
+                    server.connected();
+                    return result.map(|_| ldap);
+                }
+            }
+        }
+        Err(LdapFailure::Backend(format!("no ldap server is available ({})", errors.join(", "))))
+    }
+}
+
+//A failed bind is an Ok with a result code, success() turns it into an error
+//Result codes are rejections by the server, except busy, unavailable and unwilling, those and other errors mean the server failed
+async fn bind(ldap: &mut Ldap, timeout: Duration, dn: &str, password: &str) -> Result<(), LdapFailure> {
+    match ldap.with_timeout(timeout).simple_bind(dn, password).await.and_then(|result| result.success()) {
+        Ok(_) => Ok(()),
+        //RC 49 means invalid credentials, Active Directory tells why in the message
+        Err(LdapError::LdapResult { result }) if result.rc == 49 => Err(LdapFailure::Rejected(rejection_reason(&result.text))),
+        //51 busy, 52 unavailable and 53 unwilling to perform, another server may answer
+        Err(LdapError::LdapResult { result }) if matches!(result.rc, 51..=53) => {
+            Err(LdapFailure::Backend(format!("bind failed: {}", result)))
+        }
+        Err(LdapError::LdapResult { result }) => Err(LdapFailure::Rejected(format!("bind failed: {}", result))),
+        Err(e) => Err(LdapFailure::Backend(format!("bind failed: {}", e))),
+    }
+}
+
+//The reason of a bind rejected with invalid credentials, Active Directory tells why in a sub code of the message
+//The reason is part of the rejection that ldap_auth logs, the TokenReview doesn't tell callers if an account exists or is locked
+//e.g. "80090308: LdapErr: DSID-0C09042A, comment: AcceptSecurityContext error, data 775, v3839"
+fn rejection_reason(text: &str) -> String {
+    match ad_sub_code(text) {
+        Some(code) => format!("{} (AD error {})", ad_bind_error(&code).unwrap_or("invalid credentials"), code),
+        None => String::from("invalid credentials"),
+    }
+}
+
+//The sub code after "data " in the message of Active Directory, lowercase
+fn ad_sub_code(text: &str) -> Option<String> {
+    let code = text.split("data ").nth(1)?.split(',').next()?.trim().to_lowercase();
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(code)
+}
+
+fn ad_bind_error(code: &str) -> Option<&'static str> {
+    match code {
+        "525" => Some("user not found"),
+        "52e" => Some("invalid credentials"),
+        "530" => Some("not permitted to log on at this time"),
+        "531" => Some("not permitted to log on at this workstation"),
+        "532" => Some("password expired"),
+        "533" => Some("account disabled"),
+        "701" => Some("account expired"),
+        "773" => Some("password must be changed"),
+        "775" => Some("account locked out"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::Arc,
+    };
+
+    use super::*;
+
+    //A minimal ldap server, answers every bind with rc and every extended request with success
+    //Returns the url and the number of binds it received
+    fn start_server(rc: u8) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let binds = Arc::new(AtomicUsize::new(0));
+        let counted = binds.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let binds = counted.clone();
+                std::thread::spawn(move || serve(stream, rc, &binds));
+            }
+        });
+        (url, binds)
+    }
+
+    //Reads messages with short lengths only, which is all a simple bind needs
+    fn serve(mut stream: std::net::TcpStream, rc: u8, binds: &AtomicUsize) {
+        loop {
+            let mut header = [0u8; 2];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            let mut message = vec![0u8; header[1] as usize];
+            if stream.read_exact(&mut message).is_err() {
+                return;
+            }
+            //message is the message id INTEGER followed by the operation
+            let id = &message[..2 + message[1] as usize];
+            let (tag, rc) = match message[id.len()] {
+                0x60 => {
+                    binds.fetch_add(1, Ordering::SeqCst);
+                    (0x61, rc)
+                }
+                0x77 => (0x78, 0),
+                _ => return,
+            };
+            let operation = [tag, 0x07, 0x0a, 0x01, rc, 0x04, 0x00, 0x04, 0x00];
+            let mut response = vec![0x30, (id.len() + operation.len()) as u8];
+            response.extend_from_slice(id);
+            response.extend_from_slice(&operation);
+            if stream.write_all(&response).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn pool(urls: &[String], failover: Failover, size: usize, timeout: Duration, idle_timeout: Duration) -> LdapPool {
+        LdapPool::new(
+            urls,
+            failover,
+            LdapConnSettings::new(),
+            "cn=svc,dc=example",
+            "svcpw",
+            PoolLimits {
+                size,
+                timeout,
+                idle_timeout,
+            },
+        )
+    }
+
+    //The url of a port nothing listens on
+    fn dead_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ldap://{}", listener.local_addr().unwrap())
+    }
+
+    fn failures(pool: &LdapPool, server: usize) -> u32 {
+        pool.servers[server].health.lock().unwrap().failures
+    }
+
+    #[test]
+    fn servers_are_tried_in_failover_order() {
+        let urls: Vec<String> = ["ldap://a", "ldap://b", "ldap://c"].iter().map(|u| u.to_string()).collect();
+        let order = |pool: &LdapPool| pool.server_order().iter().map(|s| s.url.clone()).collect::<Vec<String>>();
+
+        let priority = pool(&urls, Failover::Priority, 1, Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(order(&priority), vec!["ldap://a", "ldap://b", "ldap://c"]);
+        }
+
+        let round_robin = pool(&urls, Failover::RoundRobin, 1, Duration::from_secs(1), Duration::from_secs(60));
+        assert_eq!(order(&round_robin), vec!["ldap://a", "ldap://b", "ldap://c"]);
+        assert_eq!(order(&round_robin), vec!["ldap://b", "ldap://c", "ldap://a"]);
+        assert_eq!(order(&round_robin), vec!["ldap://c", "ldap://a", "ldap://b"]);
+        assert_eq!(order(&round_robin), vec!["ldap://a", "ldap://b", "ldap://c"]);
+    }
+
+    #[test]
+    fn the_backoff_doubles_and_resets() {
+        let server = Server {
+            url: String::from("ldap://a"),
+            health: Mutex::new(Health::default()),
+        };
+        let timeout = Duration::from_secs(5);
+        let backoff = |server: &Server| server.health.lock().unwrap().retry_at.unwrap() - Instant::now();
+
+        assert!(server.try_connect(timeout));
+        server.failed();
+        assert!(backoff(&server) <= MIN_BACKOFF);
+        assert!(!server.try_connect(timeout));
+        server.failed();
+        assert!(backoff(&server) > MIN_BACKOFF && backoff(&server) <= MIN_BACKOFF * 2);
+        server.failed();
+        assert!(backoff(&server) > MIN_BACKOFF * 2 && backoff(&server) <= MIN_BACKOFF * 4);
+        for _ in 0..20 {
+            server.failed();
+        }
+        assert!(backoff(&server) > MAX_BACKOFF - Duration::from_secs(1) && backoff(&server) <= MAX_BACKOFF);
+
+        //After the backoff one connect claims the retry, the others still skip the server
+        server.health.lock().unwrap().retry_at = Some(Instant::now() - Duration::from_millis(1));
+        assert!(server.try_connect(timeout));
+        assert!(!server.try_connect(timeout));
+
+        server.connected();
+        assert_eq!(server.health.lock().unwrap().failures, 0);
+        assert!(server.try_connect(timeout));
+        assert!(server.try_connect(timeout));
+    }
+
+    #[rocket::async_test]
+    async fn dead_servers_are_skipped() {
+        let (url, binds) = start_server(0);
+        let pool = pool(&[dead_server(), url], Failover::Priority, 2, Duration::from_secs(2), Duration::from_secs(60));
+
+        let first = pool.service_connection().await.unwrap();
+        assert_eq!(failures(&pool, 0), 1);
+        //The circuit of the dead server is open, the second connect goes to the other server right away
+        let _second = pool.service_connection().await.unwrap();
+        assert_eq!(failures(&pool, 0), 1);
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+        drop(first);
+
+        assert!(pool.check_password("cn=alice,dc=example", "alicepw").await.is_ok());
+        assert_eq!(binds.load(Ordering::SeqCst), 3);
+    }
+
+    #[rocket::async_test]
+    async fn busy_servers_fail_over_for_the_service_account_only() {
+        //52 unavailable
+        let (busy, busy_binds) = start_server(52);
+        let (url, binds) = start_server(0);
+        let pool = pool(&[busy, url], Failover::Priority, 2, Duration::from_secs(2), Duration::from_secs(60));
+
+        //The user bind is not repeated on the next server and doesn't open the circuit
+        match pool.check_password("cn=alice,dc=example", "alicepw").await {
+            Err(LdapFailure::Backend(e)) => assert!(e.contains("bind failed"), "{}", e),
+            _ => panic!("a busy server is a backend failure"),
+        }
+        assert_eq!(busy_binds.load(Ordering::SeqCst), 1);
+        assert_eq!(binds.load(Ordering::SeqCst), 0);
+        assert_eq!(failures(&pool, 0), 0);
+
+        //The service account fails over and opens the circuit
+        drop(pool.service_connection().await.unwrap());
+        assert_eq!(busy_binds.load(Ordering::SeqCst), 2);
+        assert_eq!(binds.load(Ordering::SeqCst), 1);
+        assert_eq!(failures(&pool, 0), 1);
+
+        //Now user binds skip the busy server too
+        assert!(pool.check_password("cn=alice,dc=example", "alicepw").await.is_ok());
+        assert_eq!(busy_binds.load(Ordering::SeqCst), 2);
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+    }
+
+    #[rocket::async_test]
+    async fn rejected_binds_are_not_repeated() {
+        let (first, first_binds) = start_server(49);
+        let (second, second_binds) = start_server(0);
+        let pool = pool(&[first, second], Failover::Priority, 2, Duration::from_secs(2), Duration::from_secs(60));
+
+        assert!(matches!(
+            pool.check_password("cn=alice,dc=example", "wrong").await,
+            Err(LdapFailure::Rejected(_))
+        ));
+        assert_eq!(first_binds.load(Ordering::SeqCst), 1);
+        assert_eq!(second_binds.load(Ordering::SeqCst), 0);
+        assert_eq!(failures(&pool, 0), 0);
+    }
+
+    #[test]
+    fn active_directory_sub_codes_are_the_reason() {
+        let message = |code: &str| format!("80090308: LdapErr: DSID-0C09042A, comment: AcceptSecurityContext error, data {}, v3839", code);
+
+        assert_eq!(rejection_reason(&message("775")), "account locked out (AD error 775)");
+        assert_eq!(rejection_reason(&message("52e")), "invalid credentials (AD error 52e)");
+        assert_eq!(rejection_reason(&message("52E")), "invalid credentials (AD error 52e)");
+        assert_eq!(rejection_reason(&message("532")), "password expired (AD error 532)");
+        assert_eq!(rejection_reason(&message("533")), "account disabled (AD error 533)");
+        assert_eq!(rejection_reason(&message("773")), "password must be changed (AD error 773)");
+        //Unknown codes are kept, so they can be looked up
+        assert_eq!(rejection_reason(&message("568")), "invalid credentials (AD error 568)");
+        //The sub code may be the end of the message
+        assert_eq!(rejection_reason("AcceptSecurityContext error, data 701"), "account expired (AD error 701)");
+
+        //Other servers have no sub code
+        assert_eq!(rejection_reason(""), "invalid credentials");
+        assert_eq!(rejection_reason("Invalid credentials"), "invalid credentials");
+        assert_eq!(rejection_reason("no data for user, data , v1"), "invalid credentials");
+        assert_eq!(rejection_reason("data xyz, v1"), "invalid credentials");
+    }
+
+    #[rocket::async_test]
+    async fn idle_connections_expire() {
+        let (url, binds) = start_server(0);
+        let pool = pool(&[url], Failover::Priority, 2, Duration::from_secs(2), Duration::from_millis(200));
+
+        //Two connections returned to the pool, the older one is past the idle timeout
+        let first = pool.service_connection().await.unwrap();
+        let second = pool.service_connection().await.unwrap();
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+        drop(first);
+        drop(second);
+        pool.idle.lock().unwrap()[0].idle_since = Instant::now() - Duration::from_millis(300);
+
+        let idle = pool.take_idle().unwrap();
+        assert!(idle.idle_since.elapsed() < Duration::from_millis(200));
+        assert!(pool.idle.lock().unwrap().is_empty());
+        assert!(pool.take_idle().is_none());
+
+        //A reused connection needs no new bind, an expired one does
+        pool.idle.lock().unwrap().push(idle);
+        drop(pool.service_connection().await.unwrap());
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        drop(pool.service_connection().await.unwrap());
+        assert_eq!(binds.load(Ordering::SeqCst), 3);
+    }
+
+    #[rocket::async_test]
+    async fn waiting_for_a_slot_times_out() {
+        let pool = pool(&[String::from("ldap://127.0.0.1:1")], Failover::Priority, 1, Duration::from_millis(100), Duration::from_secs(60));
+
+        let slot = pool.wait_for_slot(&pool.service_slots).await.unwrap();
+        let started = Instant::now();
+        let error = pool.wait_for_slot(&pool.service_slots).await.err().unwrap();
+        assert_eq!(error, "timed out waiting for a free ldap connection");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        //The bind slots are separate
+        assert!(pool.wait_for_slot(&pool.bind_slots).await.is_ok());
+        drop(slot);
+        assert!(pool.wait_for_slot(&pool.service_slots).await.is_ok());
+    }
+}
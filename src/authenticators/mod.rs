@@ -1,3 +1,23 @@
 pub mod authenticator;
+pub mod exec;
+pub mod file_store;
+pub mod htpasswd;
+pub mod http;
+pub mod introspection;
 pub mod json;
-pub mod ldap;
\ No newline at end of file
+pub mod jwt;
+pub mod k8s_sa;
+pub mod kubeconfig;
+pub mod ldap;
+pub mod ldap_pool;
+pub mod login_token;
+pub mod oidc;
+pub mod radius;
+pub mod script;
+pub mod ssh_sig;
+#[cfg(test)]
+pub mod test_server;
+pub mod token_file;
+pub mod totp;
+pub mod wasm;
+pub mod webhook;
\ No newline at end of file
@@ -0,0 +1,504 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use jsonwebtoken::Validation;
+use rocket::tokio::sync::Mutex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::jwt::{self, ClaimMapping, JwtKey};
+
+/*
+This is an oidc authenticator that accepts id tokens of several issuers.
+The keys of an issuer are found with its discovery document (.well-known/openid-configuration)
+and cached. An unknown kid triggers a refresh of the keys, at most once per MIN_REFRESH_INTERVAL,
+so tokens with random kids can't be used to flood the issuer with requests.
+*/
+
+//Keys are not refreshed more often than this, even for unknown kids
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+//Keys are refreshed after this time, so removed keys are not trusted forever
+const MAX_KEY_AGE: Duration = Duration::from_secs(3600);
+
+//Timeout of the requests to the issuers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//The oidc config file
+#[derive(Deserialize)]
+struct OidcConfig {
+    issuers: Vec<IssuerConfig>,
+}
+
+//The config of a single issuer
+#[derive(Deserialize)]
+struct IssuerConfig {
+    //Has to match the iss claim and the issuer of the discovery document exactly
+    issuer: String,
+    //Accepted aud claims if the TokenReview has no audiences, usually the client id
+    audiences: Vec<String>,
+    #[serde(default = "default_username_claim")]
+    username_claim: String,
+    #[serde(default = "default_uid_claim")]
+    uid_claim: String,
+    #[serde(default = "default_groups_claim")]
+    groups_claim: String,
+    //Prefixes added to usernames, uids and groups, so users of different issuers can't collide
+    #[serde(default)]
+    username_prefix: String,
+    #[serde(default)]
+    groups_prefix: String,
+    //Ca file to verify the issuer with, the system roots are used if not set
+    ca_file: Option<String>,
+    #[serde(default = "default_leeway")]
+    leeway: u64,
+}
+
+fn default_username_claim() -> String {
+    String::from("sub")
+}
+
+fn default_uid_claim() -> String {
+    String::from("sub")
+}
+
+fn default_groups_claim() -> String {
+    String::from("groups")
+}
+
+fn default_leeway() -> u64 {
+    jwt::DEFAULT_LEEWAY
+}
+
+//The discovery document of an issuer, only the fields used here
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    issuer: String,
+    jwks_uri: String,
+}
+
+//An issuer with its cached keys
+struct Issuer {
+    config: IssuerConfig,
+    claims: ClaimMapping,
+    client: reqwest::Client,
+    keys: RwLock<Arc<Vec<JwtKey>>>,
+    //When the keys were fetched successfully
+    fetched: RwLock<Option<Instant>>,
+    //When the last refresh was tried, also serializes the refreshes
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl Issuer {
+    fn keys(&self) -> Arc<Vec<JwtKey>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    fn keys_expired(&self) -> bool {
+        match *self.fetched.read().unwrap() {
+            Some(fetched) => fetched.elapsed() > MAX_KEY_AGE,
+            None => true,
+        }
+    }
+
+    //Refresh the keys, unless they were refreshed less than MIN_REFRESH_INTERVAL ago
+    async fn refresh_keys(&self) {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if let Some(last) = *last_refresh {
+            if last.elapsed() < MIN_REFRESH_INTERVAL {
+                return;
+            }
+        }
+        *last_refresh = Some(Instant::now());
+
+        match self.fetch_keys().await {
+            Ok(keys) => {
+                println!("Fetched {} keys of oidc issuer {}", keys.len(), self.config.issuer);
+                *self.keys.write().unwrap() = Arc::new(keys);
+                *self.fetched.write().unwrap() = Some(Instant::now());
+            }
+            //The previous keys are kept, an issuer that is down shouldn't lock out everyone
+            Err(e) => println!("Failed to fetch keys of oidc issuer {}: {}", self.config.issuer, e),
+        }
+    }
+
+    //Fetch the keys using the discovery document
+    async fn fetch_keys(&self) -> Result<Vec<JwtKey>, String> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches("/")
+        );
+        let discovery: DiscoveryDocument = self.get_json(&discovery_url).await?;
+
+        if discovery.issuer != self.config.issuer {
+            return Err(format!(
+                "discovery document is for issuer {}, not {}",
+                discovery.issuer, self.config.issuer
+            ));
+        }
+
+        let jwks: Value = self.get_json(&discovery.jwks_uri).await?;
+        let keys = jwt::keys_from_jwks(&jwks);
+        if keys.is_empty() {
+            return Err(String::from("JWKS has no supported RS256, ES256 or EdDSA key"));
+        }
+        Ok(keys)
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, url: &str) -> Result<T, String> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| format!("request to {} failed: {}", url, e))?;
+
+        if !response.status().is_success() {
+            return Err(format!("{} returned {}", url, response.status()));
+        }
+
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| format!("invalid response of {}: {}", url, e))
+    }
+}
+
+//Auther struct for oidc id tokens of several issuers
+pub struct OidcAuthenticator {
+    issuers: Vec<Issuer>,
+}
+
+impl Authenticator for OidcAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        //Only JWTs are meant for this authenticator
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(_) => return AuthResult::denied(),
+        };
+
+        //The issuer is needed to know which keys to verify with, the claim is checked again during verification
+        let iss = match OidcAuthenticator::unverified_issuer(token) {
+            Some(iss) => iss,
+            None => return AuthResult::denied(),
+        };
+        let issuer = match self.issuers.iter().find(|i| i.config.issuer == iss) {
+            Some(issuer) => issuer,
+            None => return AuthResult::denied(),
+        };
+
+        if issuer.keys_expired() {
+            issuer.refresh_keys().await;
+        }
+
+        let mut keys = issuer.keys();
+        if jwt::candidate_keys(&keys, &header).is_empty() {
+            //Probably the issuer rotated its keys
+            issuer.refresh_keys().await;
+            keys = issuer.keys();
+        }
+        let candidates = jwt::candidate_keys(&keys, &header);
+        if candidates.is_empty() {
+            println!("No key of oidc issuer {} for kid {:?}", iss, header.kid);
+            return AuthResult::denied();
+        }
+
+        //The audiences of the TokenReview take precedence over the configured ones
+        let wanted = if audiences.is_empty() { &issuer.config.audiences[..] } else { audiences };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&issuer.config.issuer]);
+        validation.set_audience(wanted);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.validate_nbf = true;
+        validation.leeway = issuer.config.leeway;
+
+        let claims = match jwt::verify(token, &candidates, &validation) {
+            Ok(claims) => claims,
+            Err(e) => {
+                println!("Invalid oidc token of issuer {}: {}", iss, e);
+                return AuthResult::denied();
+            }
+        };
+
+        let (username, uid, groups) = match issuer.claims.map(&claims) {
+            Some(user) => user,
+            None => {
+                println!("oidc token of issuer {} has no {} claim", iss, issuer.claims.username);
+                return AuthResult::denied();
+            }
+        };
+
+        let username = format!("{}{}", issuer.config.username_prefix, username);
+        //The uid gets the prefix too, an empty uid becomes the prefixed username
+        let uid = if uid.is_empty() { uid } else { format!("{}{}", issuer.config.username_prefix, uid) };
+        let groups = groups
+            .iter()
+            .map(|g| format!("{}{}", issuer.config.groups_prefix, g))
+            .collect();
+
+        println!("Authenticated user {} with oidc issuer {}", username, iss);
+        let mut result = AuthResult::granted(&username, &uid, groups);
+        result.audiences = jwt::matched_audiences(&claims, wanted);
+        result
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading oidc_auth authenticator");
+
+        //Get --oidc_config_file argument, required
+        let config_file = match arguments.get("oidc_config_file") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--oidc_config_file is required for oidc_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        let config: OidcConfig = match fs::read_to_string(&config_file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to load --oidc_config_file {}: {}", config_file, e);
+                std::process::exit(1);
+            }
+        };
+
+        if config.issuers.is_empty() {
+            println!("--oidc_config_file {} has no issuers", config_file);
+            std::process::exit(1);
+        }
+
+        let mut issuers: Vec<Issuer> = vec![];
+        for config in config.issuers {
+            if config.audiences.is_empty() {
+                println!("oidc issuer {} needs at least one audience", config.issuer);
+                std::process::exit(1);
+            }
+            if !config.issuer.starts_with("https://") {
+                println!("WARNING: oidc issuer {} is not using https", config.issuer);
+            }
+
+            let mut client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+            if let Some(ca_file) = &config.ca_file {
+                let certificate = fs::read(ca_file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|pem| reqwest::Certificate::from_pem(&pem).map_err(|e| e.to_string()));
+                match certificate {
+                    Ok(certificate) => client = client.add_root_certificate(certificate),
+                    Err(e) => {
+                        println!("Invalid ca_file {} of oidc issuer {}: {}", ca_file, config.issuer, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let client = match client.build() {
+                Ok(client) => client,
+                Err(e) => {
+                    println!("Failed to create http client for oidc issuer {}: {}", config.issuer, e);
+                    std::process::exit(1);
+                }
+            };
+
+            //Keys are fetched with the first token, so kubeauth starts even if an issuer is down
+            issuers.push(Issuer {
+                claims: ClaimMapping {
+                    username: config.username_claim.clone(),
+                    uid: config.uid_claim.clone(),
+                    groups: config.groups_claim.clone(),
+                },
+                config,
+                client,
+                keys: RwLock::new(Arc::new(vec![])),
+                fetched: RwLock::new(None),
+                last_refresh: Mutex::new(None),
+            });
+        }
+
+        OidcAuthenticator { issuers }
+    }
+}
+
+impl OidcAuthenticator {
+    //Read the iss claim without verifying the token
+    fn unverified_issuer(token: &str) -> Option<String> {
+        let payload = token.split(".").nth(1)?;
+        let payload = BASE64_URL.decode(payload).ok()?;
+        let claims: Value = serde_json::from_slice(&payload).ok()?;
+        claims["iss"].as_str().map(|iss| iss.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::authenticators::test_server::TestServer;
+
+    //An Ed25519 signing key with its JWK
+    struct TestKey {
+        kid: String,
+        pkcs8: Vec<u8>,
+    }
+
+    impl TestKey {
+        fn new(kid: &str) -> TestKey {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            TestKey {
+                kid: kid.to_string(),
+                pkcs8: pkcs8.as_ref().to_vec(),
+            }
+        }
+
+        fn jwk(&self) -> Value {
+            let pair = Ed25519KeyPair::from_pkcs8(&self.pkcs8).unwrap();
+            json!({"kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "kid": self.kid, "x": BASE64_URL.encode(pair.public_key().as_ref())})
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.clone());
+            jsonwebtoken::encode(&header, claims, &EncodingKey::from_ed_der(&self.pkcs8)).unwrap()
+        }
+    }
+
+    //A mock issuer serving the discovery document and the keys in jwks
+    fn start_issuer(jwks: Arc<StdMutex<Value>>, discovery_issuer: Option<&str>) -> TestServer {
+        let discovery_issuer = discovery_issuer.map(|i| i.to_string());
+        TestServer::start(move |request| {
+            let url = format!("http://{}", request.headers["host"]);
+            match request.path.as_str() {
+                "/.well-known/openid-configuration" => (
+                    200,
+                    json!({"issuer": discovery_issuer.clone().unwrap_or(url.clone()), "jwks_uri": format!("{}/jwks", url)})
+                        .to_string(),
+                ),
+                "/jwks" => (200, jwks.lock().unwrap().to_string()),
+                _ => (404, String::new()),
+            }
+        })
+    }
+
+    fn authenticator(issuer: &str) -> OidcAuthenticator {
+        let config = json!({"issuers": [{
+            "issuer": issuer,
+            "audiences": ["kubeauth"],
+            "username_claim": "email",
+            "uid_claim": "sub",
+            "username_prefix": "oidc:",
+            "groups_prefix": "oidc:"
+        }]});
+        let path = std::env::temp_dir().join(format!("kubeauth-oidc-test-{}.json", issuer.replace([':', '/'], "_")));
+        fs::write(&path, config.to_string()).unwrap();
+        let mut arguments = HashMap::new();
+        arguments.insert(String::from("oidc_config_file"), vec![path.to_string_lossy().to_string()]);
+        OidcAuthenticator::new(arguments)
+    }
+
+    fn claims(issuer: &str, aud: &str) -> Value {
+        let now = chrono::Utc::now().timestamp();
+        json!({"iss": issuer, "aud": aud, "sub": "1234", "email": "alice@example.com", "groups": ["dev"], "exp": now + 300, "iat": now})
+    }
+
+    #[rocket::async_test]
+    async fn discovery_keys_and_prefixes() {
+        let key = TestKey::new("k1");
+        let jwks = Arc::new(StdMutex::new(json!({"keys": [key.jwk()]})));
+        let server = start_issuer(jwks, None);
+        let oidc = authenticator(&server.url);
+
+        let result = oidc.auth(&key.sign(&claims(&server.url, "kubeauth")), &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "oidc:alice@example.com");
+        assert_eq!(result.uid, "oidc:1234");
+        assert_eq!(result.groups, vec!["oidc:dev"]);
+        assert_eq!(result.audiences, vec!["kubeauth"]);
+
+        //The keys are cached
+        assert!(oidc.auth(&key.sign(&claims(&server.url, "kubeauth")), &[]).await.authenticated);
+        assert_eq!(server.count("/.well-known/openid-configuration"), 1);
+        assert_eq!(server.count("/jwks"), 1);
+    }
+
+    #[rocket::async_test]
+    async fn audiences_of_the_token_review_replace_the_configured_ones() {
+        let key = TestKey::new("k1");
+        let server = start_issuer(Arc::new(StdMutex::new(json!({"keys": [key.jwk()]}))), None);
+        let oidc = authenticator(&server.url);
+
+        let token = key.sign(&claims(&server.url, "kubeauth"));
+        assert!(!oidc.auth(&token, &[String::from("other")]).await.authenticated);
+        let token = key.sign(&claims(&server.url, "other"));
+        assert!(oidc.auth(&token, &[String::from("other")]).await.authenticated);
+        assert!(!oidc.auth(&token, &[]).await.authenticated);
+    }
+
+    #[rocket::async_test]
+    async fn foreign_keys_and_issuers_are_denied() {
+        let key = TestKey::new("k1");
+        let server = start_issuer(Arc::new(StdMutex::new(json!({"keys": [key.jwk()]}))), None);
+        let oidc = authenticator(&server.url);
+
+        //Same kid, different key
+        let forged = TestKey::new("k1").sign(&claims(&server.url, "kubeauth"));
+        assert!(!oidc.auth(&forged, &[]).await.authenticated);
+        //Issuer that isn't configured
+        let token = key.sign(&claims("https://other.example.com", "kubeauth"));
+        assert!(!oidc.auth(&token, &[]).await.authenticated);
+    }
+
+    #[rocket::async_test]
+    async fn discovery_document_of_another_issuer_is_rejected() {
+        let key = TestKey::new("k1");
+        let server = start_issuer(
+            Arc::new(StdMutex::new(json!({"keys": [key.jwk()]}))),
+            Some("https://evil.example.com"),
+        );
+        let oidc = authenticator(&server.url);
+
+        assert!(!oidc.auth(&key.sign(&claims(&server.url, "kubeauth")), &[]).await.authenticated);
+        assert_eq!(server.count("/jwks"), 0);
+    }
+
+    #[rocket::async_test]
+    async fn rotated_keys_are_fetched_for_unknown_kids() {
+        let old = TestKey::new("old");
+        let new = TestKey::new("new");
+        let jwks = Arc::new(StdMutex::new(json!({"keys": [old.jwk()]})));
+        let server = start_issuer(jwks.clone(), None);
+        let oidc = authenticator(&server.url);
+
+        assert!(oidc.auth(&old.sign(&claims(&server.url, "kubeauth")), &[]).await.authenticated);
+
+        //The issuer rotates to the new key
+        *jwks.lock().unwrap() = json!({"keys": [new.jwk()]});
+        let token = new.sign(&claims(&server.url, "kubeauth"));
+
+        //Unknown kids don't refresh the keys more than once per MIN_REFRESH_INTERVAL
+        assert!(!oidc.auth(&token, &[]).await.authenticated);
+        assert_eq!(server.count("/jwks"), 1);
+
+        *oidc.issuers[0].last_refresh.lock().await = Some(Instant::now() - MIN_REFRESH_INTERVAL);
+        assert!(oidc.auth(&token, &[]).await.authenticated);
+        assert_eq!(server.count("/jwks"), 2);
+
+        //The old key was removed by the issuer
+        assert!(!oidc.auth(&old.sign(&claims(&server.url, "kubeauth")), &[]).await.authenticated);
+    }
+}
@@ -0,0 +1,618 @@
+use std::{collections::HashMap, fs, net::SocketAddr, time::Duration};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use md5::{Digest, Md5};
+use rocket::tokio::{net::UdpSocket, time::timeout};
+use subtle::ConstantTimeEq;
+
+use super::authenticator::{argument_values, AuthResult, Authenticator};
+
+/*
+This is a RADIUS authenticator (RFC 2865), for user:password tokens.
+The password is sent with PAP or CHAP in an Access-Request. The servers are tried in order,
+the next server is only asked if the previous one didn't answer, a reject is final.
+Requests and responses are signed with a Message-Authenticator (RFC 3579).
+Groups are taken from the Filter-Id and Class attributes of the Access-Accept.
+*/
+
+//Packet codes
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCESS_CHALLENGE: u8 = 11;
+
+//Attribute types
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const CHAP_PASSWORD: u8 = 3;
+const FILTER_ID: u8 = 11;
+const CLASS: u8 = 25;
+const NAS_IDENTIFIER: u8 = 32;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const DEFAULT_PORT: u16 = 1812;
+const DEFAULT_TIMEOUT: u64 = 3;
+const DEFAULT_RETRIES: u32 = 1;
+
+//PAP passwords are at most 128 bytes
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+//Code and attributes of a response
+type Response = (u8, Vec<(u8, Vec<u8>)>);
+
+//How the password is sent to the server
+#[derive(PartialEq)]
+enum AuthMethod {
+    Pap,
+    Chap,
+}
+
+//Auther struct for RADIUS servers
+pub struct RadiusAuthenticator {
+    servers: Vec<String>,
+    secret: Vec<u8>,
+    method: AuthMethod,
+    nas_identifier: String,
+    timeout: Duration,
+    retries: u32,
+    //Attribute types the groups are taken from
+    group_attributes: Vec<u8>,
+    //Responses without Message-Authenticator are ignored
+    require_message_authenticator: bool,
+}
+
+impl Authenticator for RadiusAuthenticator {
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        //Split token by :, tokens without : are not meant for this authenticator
+        let (username, password) = match token.split_once(":") {
+            Some(split) => split,
+            None => return AuthResult::denied(),
+        };
+        //Longer usernames would be cut by push_attribute, and the server would check another user
+        if username.is_empty() || username.len() > 253 || password.is_empty() || password.len() > MAX_PASSWORD_LENGTH {
+            return AuthResult::denied();
+        }
+
+        println!("Authenticating user {} with radius", username);
+
+        for server in &self.servers {
+            for _ in 0..=self.retries {
+                match self.access_request(server, username, password).await {
+                    Ok(Some(groups)) => {
+                        println!("Authenticated user {} with radius server {}", username, server);
+                        return AuthResult::granted(username, username, groups);
+                    }
+                    Ok(None) => return AuthResult::denied(),
+                    Err(e) => println!("Radius server {}: {}", server, e),
+                }
+            }
+        }
+
+        println!("No radius server answered for user {}", username);
+        AuthResult::denied()
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading radius_auth authenticator");
+
+        //Get --radius_servers argument, required, host or host:port
+        let servers: Vec<String> = match argument_values(&arguments, "radius_servers") {
+            Some(servers) if !servers.is_empty() => servers
+                .iter()
+                .map(|server| match server.rsplit_once(":") {
+                    Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with("]") => server.clone(),
+                    _ => format!("{}:{}", server, DEFAULT_PORT),
+                })
+                .collect(),
+            _ => {
+                println!("--radius_servers is required for radius_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Get the shared secret from --radius_secret_file, so it doesn't show up in the process list
+        let secret = match arguments.get("radius_secret_file") {
+            Some(path) if !path.is_empty() => match fs::read_to_string(&path[0]) {
+                Ok(secret) if !secret.trim().is_empty() => secret.trim().as_bytes().to_vec(),
+                Ok(_) => {
+                    println!("--radius_secret_file {} is empty", path[0]);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    println!("Failed to read --radius_secret_file {}: {}", path[0], e);
+                    std::process::exit(1);
+                }
+            },
+            _ => {
+                println!("--radius_secret_file is required for radius_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Get --radius_auth_method argument, default to pap
+        let method = match arguments.get("radius_auth_method").map(|m| m[0].to_lowercase()) {
+            None => AuthMethod::Pap,
+            Some(method) if method == "pap" => AuthMethod::Pap,
+            Some(method) if method == "chap" => AuthMethod::Chap,
+            Some(method) => {
+                println!("--radius_auth_method {} is not supported, use pap or chap", method);
+                std::process::exit(1);
+            }
+        };
+
+        //Get --radius_timeout argument in seconds
+        let timeout = match arguments.get("radius_timeout") {
+            Some(timeout) => match timeout[0].parse::<u64>() {
+                Ok(timeout) if timeout > 0 => timeout,
+                _ => {
+                    println!("--radius_timeout is not a valid number of seconds");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_TIMEOUT,
+        };
+
+        //Get --radius_retries argument
+        let retries = match arguments.get("radius_retries") {
+            Some(retries) => match retries[0].parse::<u32>() {
+                Ok(retries) => retries,
+                Err(_) => {
+                    println!("--radius_retries is not a valid number");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_RETRIES,
+        };
+
+        //Get --radius_group_attributes argument, default to Filter-Id and Class
+        let group_attributes = match argument_values(&arguments, "radius_group_attributes") {
+            Some(attributes) => attributes
+                .iter()
+                .map(|attribute| match attribute.to_lowercase().as_str() {
+                    "filter-id" => FILTER_ID,
+                    "class" => CLASS,
+                    _ => {
+                        println!("--radius_group_attributes {} is not supported, use Filter-Id or Class", attribute);
+                        std::process::exit(1);
+                    }
+                })
+                .collect(),
+            None => vec![FILTER_ID, CLASS],
+        };
+
+        //Get --radius_require_message_authenticator argument, default to true
+        let require_message_authenticator = match arguments.get("radius_require_message_authenticator") {
+            Some(value) => value[0] != "false",
+            None => true,
+        };
+        if !require_message_authenticator {
+            println!("WARNING: radius responses without Message-Authenticator are accepted, they can be forged (BlastRADIUS)");
+        }
+
+        RadiusAuthenticator {
+            servers,
+            secret,
+            method,
+            nas_identifier: arguments
+                .get("radius_nas_identifier")
+                .map(|n| n[0].clone())
+                .unwrap_or(String::from("kubeauth")),
+            timeout: Duration::from_secs(timeout),
+            retries,
+            group_attributes,
+            require_message_authenticator,
+        }
+    }
+}
+
+impl RadiusAuthenticator {
+    //Send an Access-Request, returns the groups on accept, None on reject and Err if there was no valid answer
+    async fn access_request(&self, server: &str, username: &str, password: &str) -> Result<Option<Vec<String>>, String> {
+        let address: SocketAddr = rocket::tokio::net::lookup_host(server)
+            .await
+            .map_err(|e| format!("can not resolve: {}", e))?
+            .next()
+            .ok_or(String::from("can not resolve"))?;
+
+        let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+        socket.connect(address).await.map_err(|e| e.to_string())?;
+
+        let mut random = [0u8; 17];
+        OsRng.fill_bytes(&mut random);
+        let identifier = random[0];
+        let mut authenticator = [0u8; 16];
+        authenticator.copy_from_slice(&random[1..]);
+
+        let request = self.build_request(identifier, &authenticator, username, password);
+        socket.send(&request).await.map_err(|e| e.to_string())?;
+
+        //Wait for the answer to this request, other packets are dropped
+        let mut buffer = [0u8; 4096];
+        let deadline = rocket::tokio::time::Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(rocket::tokio::time::Instant::now());
+            let length = match timeout(remaining, socket.recv(&mut buffer)).await {
+                Ok(Ok(length)) => length,
+                Ok(Err(e)) => return Err(e.to_string()),
+                Err(_) => return Err(String::from("timeout")),
+            };
+
+            match self.parse_response(&buffer[..length], identifier, &authenticator) {
+                Some((ACCESS_ACCEPT, attributes)) => return Ok(Some(self.groups(&attributes))),
+                Some((ACCESS_REJECT, _)) => return Ok(None),
+                //A challenge would need another round with the user, which a TokenReview can't do
+                Some((ACCESS_CHALLENGE, _)) => {
+                    println!("Radius server {} sent an Access-Challenge, which is not supported", server);
+                    return Ok(None);
+                }
+                _ => println!("Radius server {} sent an invalid packet, ignoring it", server),
+            }
+        }
+    }
+
+    fn build_request(&self, identifier: u8, authenticator: &[u8; 16], username: &str, password: &str) -> Vec<u8> {
+        let mut attributes: Vec<u8> = vec![];
+        push_attribute(&mut attributes, USER_NAME, username.as_bytes());
+
+        match self.method {
+            AuthMethod::Pap => {
+                let hidden = self.hide_password(password.as_bytes(), authenticator);
+                push_attribute(&mut attributes, USER_PASSWORD, &hidden);
+            }
+            AuthMethod::Chap => {
+                //The request authenticator is used as challenge, so no CHAP-Challenge is needed
+                let chap_id = identifier;
+                let mut hash = Md5::new();
+                hash.update([chap_id]);
+                hash.update(password.as_bytes());
+                hash.update(authenticator);
+                let mut value = vec![chap_id];
+                value.extend_from_slice(&hash.finalize());
+                push_attribute(&mut attributes, CHAP_PASSWORD, &value);
+            }
+        }
+
+        push_attribute(&mut attributes, NAS_IDENTIFIER, self.nas_identifier.as_bytes());
+
+        //The Message-Authenticator is calculated with itself set to zeros
+        let message_authenticator_offset = 20 + attributes.len() + 2;
+        push_attribute(&mut attributes, MESSAGE_AUTHENTICATOR, &[0u8; 16]);
+
+        let mut packet = vec![ACCESS_REQUEST, identifier];
+        packet.extend_from_slice(&((20 + attributes.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(authenticator);
+        packet.extend_from_slice(&attributes);
+
+        let mac = hmac_md5(&self.secret, &packet);
+        packet[message_authenticator_offset..message_authenticator_offset + 16].copy_from_slice(&mac);
+        packet
+    }
+
+    //Hide a PAP password, see RFC 2865 5.2
+    fn hide_password(&self, password: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+        let mut padded = password.to_vec();
+        let padding = (16 - padded.len() % 16) % 16;
+        padded.extend(std::iter::repeat_n(0u8, padding));
+
+        let mut hidden: Vec<u8> = vec![];
+        let mut previous = authenticator.to_vec();
+        for chunk in padded.chunks(16) {
+            let mut hash = Md5::new();
+            hash.update(&self.secret);
+            hash.update(&previous);
+            let hash = hash.finalize();
+            let block: Vec<u8> = chunk.iter().zip(hash.iter()).map(|(p, h)| p ^ h).collect();
+            hidden.extend_from_slice(&block);
+            previous = block;
+        }
+        hidden
+    }
+
+    //Check a response and return its code and attributes, None if it is not a valid response to the request
+    fn parse_response(&self, packet: &[u8], identifier: u8, request_authenticator: &[u8; 16]) -> Option<Response> {
+        if packet.len() < 20 || packet[1] != identifier {
+            return None;
+        }
+        let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if length < 20 || length > packet.len() {
+            return None;
+        }
+        let packet = &packet[..length];
+
+        //Response Authenticator = MD5(Code + Identifier + Length + Request Authenticator + Attributes + Secret)
+        let mut hash = Md5::new();
+        hash.update(&packet[..4]);
+        hash.update(request_authenticator);
+        hash.update(&packet[20..]);
+        hash.update(&self.secret);
+        if !bool::from(hash.finalize().as_slice().ct_eq(&packet[4..20])) {
+            println!("Radius response has an invalid authenticator, check the shared secret");
+            return None;
+        }
+
+        let mut attributes: Vec<(u8, Vec<u8>)> = vec![];
+        let mut message_authenticator: Option<usize> = None;
+        let mut offset = 20;
+        while offset + 2 <= packet.len() {
+            let kind = packet[offset];
+            let attribute_length = packet[offset + 1] as usize;
+            if attribute_length < 2 || offset + attribute_length > packet.len() {
+                return None;
+            }
+            if kind == MESSAGE_AUTHENTICATOR {
+                if attribute_length != 18 {
+                    return None;
+                }
+                message_authenticator = Some(offset + 2);
+            }
+            attributes.push((kind, packet[offset + 2..offset + attribute_length].to_vec()));
+            offset += attribute_length;
+        }
+
+        match message_authenticator {
+            Some(position) => {
+                //Calculated over the response with the request authenticator and itself set to zeros
+                let mut copy = packet.to_vec();
+                copy[4..20].copy_from_slice(request_authenticator);
+                copy[position..position + 16].fill(0);
+                let mac = hmac_md5(&self.secret, &copy);
+                if !bool::from(mac.ct_eq(&packet[position..position + 16])) {
+                    println!("Radius response has an invalid Message-Authenticator");
+                    return None;
+                }
+            }
+            None if self.require_message_authenticator => {
+                println!("Radius response has no Message-Authenticator");
+                return None;
+            }
+            None => {}
+        }
+
+        Some((packet[0], attributes))
+    }
+
+    //Get the groups from the configured attributes
+    fn groups(&self, attributes: &[(u8, Vec<u8>)]) -> Vec<String> {
+        let mut groups: Vec<String> = vec![];
+        for (kind, value) in attributes {
+            if !self.group_attributes.contains(kind) {
+                continue;
+            }
+            let group = String::from_utf8_lossy(value).trim().to_string();
+            if !group.is_empty() && !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        groups
+    }
+}
+
+fn push_attribute(attributes: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    //Attributes are at most 253 bytes, longer values are cut
+    let value = &value[..value.len().min(253)];
+    attributes.push(kind);
+    attributes.push((value.len() + 2) as u8);
+    attributes.extend_from_slice(value);
+}
+
+//HMAC-MD5 as used by the Message-Authenticator
+fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..16].copy_from_slice(&Md5::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Md5::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let inner = inner.finalize();
+
+    let mut outer = Md5::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner);
+
+    let mut mac = [0u8; 16];
+    mac.copy_from_slice(&outer.finalize());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket as StdUdpSocket,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::*;
+
+    const SECRET: &[u8] = b"testing123";
+
+    //How the fake server answers a request
+    enum Reply {
+        Accept(Vec<(u8, &'static str)>),
+        Reject,
+        WrongAuthenticator,
+        Ignore,
+    }
+
+    struct FakeServer {
+        address: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl FakeServer {
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    //A fake RADIUS server on a free port of localhost, the reply gets the number of the request, the username and the password
+    fn start_server(reply: impl Fn(usize, &str, &str) -> Reply + Send + 'static) -> FakeServer {
+        let socket = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok((length, peer)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[..length];
+                let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let (username, password) = read_request(request);
+                let packet = match reply(count, &username, &password) {
+                    Reply::Accept(attributes) => response(ACCESS_ACCEPT, request, &attributes),
+                    Reply::Reject => response(ACCESS_REJECT, request, &[]),
+                    Reply::WrongAuthenticator => {
+                        let mut packet = response(ACCESS_ACCEPT, request, &[]);
+                        packet[4] ^= 0xff;
+                        packet
+                    }
+                    Reply::Ignore => continue,
+                };
+                let _ = socket.send_to(&packet, peer);
+            }
+        });
+        FakeServer { address, requests }
+    }
+
+    //Get the username and the PAP password of an Access-Request
+    fn read_request(request: &[u8]) -> (String, String) {
+        let mut username = String::new();
+        let mut password: Vec<u8> = vec![];
+        let mut offset = 20;
+        while offset + 2 <= request.len() {
+            let length = request[offset + 1] as usize;
+            let value = &request[offset + 2..offset + length];
+            match request[offset] {
+                USER_NAME => username = String::from_utf8_lossy(value).to_string(),
+                USER_PASSWORD => {
+                    let mut previous = request[4..20].to_vec();
+                    for chunk in value.chunks(16) {
+                        let hash = Md5::new().chain_update(SECRET).chain_update(&previous).finalize();
+                        password.extend(chunk.iter().zip(hash.iter()).map(|(c, h)| c ^ h));
+                        previous = chunk.to_vec();
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        while password.last() == Some(&0) {
+            password.pop();
+        }
+        (username, String::from_utf8_lossy(&password).to_string())
+    }
+
+    //Build a signed response with a Message-Authenticator
+    fn response(code: u8, request: &[u8], attributes: &[(u8, &str)]) -> Vec<u8> {
+        let mut body: Vec<u8> = vec![];
+        for (kind, value) in attributes {
+            push_attribute(&mut body, *kind, value.as_bytes());
+        }
+        let message_authenticator_offset = 20 + body.len() + 2;
+        push_attribute(&mut body, MESSAGE_AUTHENTICATOR, &[0u8; 16]);
+
+        let mut packet = vec![code, request[1]];
+        packet.extend_from_slice(&((20 + body.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&request[4..20]);
+        packet.extend_from_slice(&body);
+
+        let mac = hmac_md5(SECRET, &packet);
+        packet[message_authenticator_offset..message_authenticator_offset + 16].copy_from_slice(&mac);
+        let authenticator = Md5::new().chain_update(&packet).chain_update(SECRET).finalize();
+        packet[4..20].copy_from_slice(&authenticator);
+        packet
+    }
+
+    fn authenticator(servers: &[&FakeServer]) -> RadiusAuthenticator {
+        let port = servers[0].address.rsplit(":").next().unwrap();
+        let secret_file = std::env::temp_dir().join(format!("kubeauth-radius-test-secret-{}", port));
+        fs::write(&secret_file, SECRET).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(
+            String::from("radius_servers"),
+            servers.iter().map(|s| s.address.clone()).collect(),
+        );
+        arguments.insert(
+            String::from("radius_secret_file"),
+            vec![secret_file.to_string_lossy().to_string()],
+        );
+        arguments.insert(String::from("radius_timeout"), vec![String::from("1")]);
+        RadiusAuthenticator::new(arguments)
+    }
+
+    fn alice(_: usize, username: &str, password: &str) -> Reply {
+        if username == "alice" && password == "alicepw" {
+            Reply::Accept(vec![(FILTER_ID, "devs"), (CLASS, "ops"), (FILTER_ID, "devs"), (NAS_IDENTIFIER, "other")])
+        } else {
+            Reply::Reject
+        }
+    }
+
+    #[rocket::async_test]
+    async fn access_accept_grants_with_groups() {
+        let server = start_server(alice);
+        let radius = authenticator(&[&server]);
+
+        let result = radius.auth("alice:alicepw", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.uid, "alice");
+        assert_eq!(result.groups, vec!["devs", "ops"]);
+        assert_eq!(server.requests(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn access_reject_is_final() {
+        let rejecting = start_server(alice);
+        let accepting = start_server(|_, _, _| Reply::Accept(vec![]));
+        let radius = authenticator(&[&rejecting, &accepting]);
+
+        let result = radius.auth("alice:wrong", &[]).await;
+        assert!(!result.authenticated);
+        assert!(result.error.is_none());
+        //Neither retried nor asked at the next server
+        assert_eq!(rejecting.requests(), 1);
+        assert_eq!(accepting.requests(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn wrong_response_authenticators_are_ignored() {
+        let server = start_server(|_, _, _| Reply::WrongAuthenticator);
+        let radius = authenticator(&[&server]);
+
+        assert!(!radius.auth("alice:alicepw", &[]).await.authenticated);
+        //Every try waits for the timeout, the forged accept is dropped
+        assert_eq!(server.requests(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn requests_are_retransmitted_after_a_timeout() {
+        //The first request is lost, the retransmission is answered
+        let server = start_server(|count, username, password| match count {
+            1 => Reply::Ignore,
+            _ => alice(count, username, password),
+        });
+        let radius = authenticator(&[&server]);
+
+        let result = radius.auth("alice:alicepw", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(server.requests(), 2);
+
+        //A server that never answers is given up after the retries, the next server is asked
+        let silent = start_server(|_, _, _| Reply::Ignore);
+        let backup = start_server(alice);
+        let radius = authenticator(&[&silent, &backup]);
+
+        assert!(radius.auth("alice:alicepw", &[]).await.authenticated);
+        assert_eq!(silent.requests(), 2);
+        assert_eq!(backup.requests(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::HashMap, path::Path};
+
+use chrono::{Datelike, Timelike};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+/*
+Rhai scripts for rules that are too specific for the config of the authenticators.
+A script can be an authenticator itself (script_auth, fn authenticate(token, audiences))
+or change and deny the results of all authenticators (--script_transform_file, fn transform(user)).
+Rhai has no access to files, network or processes, the scripts only see the values they get
+and the time functions below. Scripts are reloaded when the file changes.
+*/
+
+//Default number of operations a script may run per call, so endless loops are stopped
+const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+
+//Create an engine with the limits and the functions available to scripts
+fn new_engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(max_operations);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(64 * 1024);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine.disable_symbol("eval");
+    //import would load modules from the file system
+    engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+    engine.on_print(|text| println!("script: {}", text));
+    engine.on_debug(|text, _, position| println!("script {}: {}", position, text));
+
+    //Time functions, all in UTC
+    engine.register_fn("timestamp", || chrono::Utc::now().timestamp());
+    //1 is monday, 7 is sunday
+    engine.register_fn("weekday", || chrono::Utc::now().weekday().number_from_monday() as i64);
+    engine.register_fn("hour", || chrono::Utc::now().hour() as i64);
+    engine
+}
+
+//Compile a script, it has to define the function name with the given number of parameters
+fn compile(content: &str, name: &str, params: usize) -> Result<AST, String> {
+    let ast = new_engine(DEFAULT_MAX_OPERATIONS)
+        .compile(content)
+        .map_err(|e| format!("Failed to compile script: {}", e))?;
+    if !ast.iter_functions().any(|f| f.name == name && f.params.len() == params) {
+        return Err(format!("Script has no function {} with {} parameters", name, params));
+    }
+    Ok(ast)
+}
+
+fn compile_authenticate(content: &str) -> Result<AST, String> {
+    compile(content, "authenticate", 2)
+}
+
+fn compile_transform(content: &str) -> Result<AST, String> {
+    compile(content, "transform", 1)
+}
+
+//Load a script file, exits if it is missing or invalid
+fn load_script(arguments: &HashMap<String, Vec<String>>, argument: &str, parse: fn(&str) -> Result<AST, String>) -> Option<FileStore<AST>> {
+    let path = match arguments.get(argument) {
+        Some(path) if !path.is_empty() => path[0].clone(),
+        _ => return None,
+    };
+    if !Path::new(&path).exists() {
+        println!("--{} is not valid", argument);
+        std::process::exit(1);
+    }
+    match FileStore::load(&path, parse) {
+        Ok(script) => Some(script),
+        Err(e) => {
+            println!("--{} {}: {}", argument, path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+//Get --script_max_operations argument
+fn max_operations(arguments: &HashMap<String, Vec<String>>) -> u64 {
+    match arguments.get("script_max_operations") {
+        Some(max_operations) => match max_operations[0].parse::<u64>() {
+            Ok(max_operations) if max_operations > 0 => max_operations,
+            _ => {
+                println!("--script_max_operations is not a valid number");
+                std::process::exit(1);
+            }
+        },
+        None => DEFAULT_MAX_OPERATIONS,
+    }
+}
+
+//The user as map for the scripts
+fn user_map(result: &AuthResult, authenticator: &str) -> Map {
+    let mut extra = Map::new();
+    for (key, values) in &result.extra {
+        extra.insert(key.into(), string_array(values).into());
+    }
+
+    let mut user = Map::new();
+    user.insert("authenticated".into(), result.authenticated.into());
+    user.insert("username".into(), result.username.clone().into());
+    user.insert("uid".into(), result.uid.clone().into());
+    user.insert("groups".into(), string_array(&result.groups).into());
+    user.insert("extra".into(), extra.into());
+    user.insert("audiences".into(), string_array(&result.audiences).into());
+    user.insert("authenticator".into(), authenticator.into());
+    user
+}
+
+//Read a user map returned by a script, missing fields are taken from the previous result
+fn user_result(user: Map, previous: AuthResult) -> Result<AuthResult, String> {
+    let string = |key: &str, previous: String| match user.get(key) {
+        Some(value) => value.clone().into_string().map_err(|_| format!("{} is not a string", key)),
+        None => Ok(previous),
+    };
+    let strings = |key: &str, previous: Vec<String>| match user.get(key) {
+        Some(value) => dynamic_strings(value).ok_or(format!("{} is not an array of strings", key)),
+        None => Ok(previous),
+    };
+
+    let authenticated = match user.get("authenticated") {
+        Some(value) => value.as_bool().map_err(|_| String::from("authenticated is not a bool"))?,
+        None => previous.authenticated,
+    };
+    let username = string("username", previous.username)?;
+    let uid = string("uid", previous.uid)?;
+    let groups = strings("groups", previous.groups)?;
+    let audiences = strings("audiences", previous.audiences)?;
+    let extra = match user.get("extra") {
+        Some(value) => {
+            let map = value.clone().try_cast::<Map>().ok_or("extra is not a map")?;
+            let mut extra: HashMap<String, Vec<String>> = HashMap::new();
+            for (key, values) in map {
+                let values = dynamic_strings(&values).ok_or(format!("extra {} is not an array of strings", key))?;
+                extra.insert(key.to_string(), values);
+            }
+            extra
+        }
+        None => previous.extra,
+    };
+
+    if !authenticated {
+        return Ok(AuthResult::denied());
+    }
+    if username.is_empty() {
+        return Err(String::from("username is empty"));
+    }
+    let mut result = AuthResult::granted(&username, &uid, groups);
+    result.audiences = audiences;
+    result.extra = extra;
+    Ok(result)
+}
+
+fn string_array(values: &[String]) -> Array {
+    values.iter().map(|v| Dynamic::from(v.clone())).collect()
+}
+
+fn dynamic_strings(value: &Dynamic) -> Option<Vec<String>> {
+    value
+        .clone()
+        .try_cast::<Array>()?
+        .into_iter()
+        .map(|v| v.into_string().ok())
+        .collect()
+}
+
+//Auther struct for rhai scripts
+pub struct ScriptAuthenticator {
+    engine: Engine,
+    script: FileStore<AST>,
+}
+
+impl Authenticator for ScriptAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let script = self.script.get();
+        let returned = self.engine.call_fn::<Dynamic>(
+            &mut Scope::new(),
+            &script,
+            "authenticate",
+            (token.to_string(), string_array(audiences)),
+        );
+
+        //Scripts deny with false or by returning nothing
+        let user = match returned {
+            Ok(returned) => match returned.try_cast::<Map>() {
+                Some(user) => user,
+                None => return AuthResult::denied(),
+            },
+            Err(e) => {
+                println!("script_auth failed: {}", e);
+                return AuthResult::denied();
+            }
+        };
+
+        //A returned user is authenticated unless it says otherwise
+        let granted = AuthResult {
+            authenticated: true,
+            ..AuthResult::default()
+        };
+        match user_result(user, granted) {
+            Ok(result) => {
+                if result.authenticated {
+                    println!("Authenticated user {} with script", result.username);
+                }
+                result
+            }
+            Err(e) => {
+                println!("script_auth returned an invalid user: {}", e);
+                AuthResult::denied()
+            }
+        }
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading script_auth authenticator");
+
+        //Get --script_auth_file argument, required
+        let script = match load_script(&arguments, "script_auth_file", compile_authenticate) {
+            Some(script) => script,
+            None => {
+                println!("--script_auth_file is required for script_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        ScriptAuthenticator {
+            engine: new_engine(max_operations(&arguments)),
+            script,
+        }
+    }
+}
+
+//Runs the transform script over the results of all authenticators
+pub struct ScriptTransform {
+    engine: Engine,
+    script: FileStore<AST>,
+}
+
+impl ScriptTransform {
+    //Get --script_transform_file argument, optional
+    pub fn new(arguments: &HashMap<String, Vec<String>>) -> Option<Self> {
+        let script = load_script(arguments, "script_transform_file", compile_transform)?;
+        println!("Loaded transform script");
+        Some(ScriptTransform {
+            engine: new_engine(max_operations(arguments)),
+            script,
+        })
+    }
+
+    //Run the script over a successful result of the authenticator
+    //The script can return a changed user, false to deny or nothing to keep the result
+    //A failing script denies, so a broken rule doesn't let everyone in
+    pub fn transform(&self, authenticator: &str, result: AuthResult) -> AuthResult {
+        let script = self.script.get();
+        let user = user_map(&result, authenticator);
+        let username = result.username.clone();
+
+        let returned = match self.engine.call_fn::<Dynamic>(&mut Scope::new(), &script, "transform", (user,)) {
+            Ok(returned) => returned,
+            Err(e) => {
+                println!("Transform script failed for user {}: {}", username, e);
+                return AuthResult::denied();
+            }
+        };
+
+        if returned.is_unit() {
+            return result;
+        }
+        if let Ok(allowed) = returned.as_bool() {
+            if !allowed {
+                println!("Transform script denied user {}", username);
+                return AuthResult::denied();
+            }
+            return result;
+        }
+        let user = match returned.try_cast::<Map>() {
+            Some(user) => user,
+            None => {
+                println!("Transform script returned neither a user nor a bool for user {}", username);
+                return AuthResult::denied();
+            }
+        };
+
+        match user_result(user, result) {
+            Ok(result) => {
+                if !result.authenticated {
+                    println!("Transform script denied user {}", username);
+                }
+                result
+            }
+            Err(e) => {
+                println!("Transform script returned an invalid user for {}: {}", username, e);
+                AuthResult::denied()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_can_not_import_files() {
+        let module = std::env::temp_dir().join(format!("kubeauth-script-test-{}.rhai", std::process::id()));
+        std::fs::write(&module, "fn secret() { \"leaked\" }").unwrap();
+        let path = module.to_string_lossy().trim_end_matches(".rhai").to_string();
+        let content = format!("fn authenticate(token, audiences) {{ import \"{}\" as m; m::secret() }}", path);
+
+        let engine = new_engine(DEFAULT_MAX_OPERATIONS);
+        let ast = compile_authenticate(&content).unwrap();
+        let result = engine.call_fn::<Dynamic>(&mut Scope::new(), &ast, "authenticate", ("", Array::new()));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Mutex,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::{
+    digest,
+    signature::{self, RsaPublicKeyComponents, UnparsedPublicKey},
+};
+use serde::Deserialize;
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+/*
+This is a ssh signature authenticator, users sign a timestamp with their ssh key instead of sending a password.
+The token is user:timestamp:signature, the signature is a ssh signature (ssh-keygen -Y sign, sshsig format)
+of "<timestamp>:<audience>" with the namespace kubeauth, base64 encoded without the armor lines.
+The signature is checked against the authorized_keys of the user in the users file (the same file as json_auth).
+Supported keys are ed25519 and RSA (rsa-sha2-256 and rsa-sha2-512).
+A timestamp is only accepted for a short time and every signature only once.
+*/
+
+//The sshsig namespace of the signatures, signatures for other purposes (e.g. git commits) are rejected
+pub const NAMESPACE: &str = "kubeauth";
+
+//Default seconds a timestamp is accepted before and after the current time
+const DEFAULT_MAX_AGE: u64 = 60;
+
+const MAGIC_PREAMBLE: &[u8] = b"SSHSIG";
+
+//A user of the users file, other fields of the users are ignored
+#[derive(Deserialize)]
+struct SshUser {
+    username: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    authorized_keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SshUsers {
+    users: Vec<SshUser>,
+}
+
+//The keys and groups of a user, keys are in ssh wire format
+struct UserKeys {
+    groups: Vec<String>,
+    keys: Vec<Vec<u8>>,
+}
+
+//Auther struct for ssh signed tokens
+pub struct SshSigAuthenticator {
+    users: FileStore<HashMap<String, UserKeys>>,
+    audience: String,
+    max_age: u64,
+    //(username, timestamp, signature) of the used tokens, kept until their timestamp is too old anyway
+    used: Mutex<HashSet<(String, u64, Vec<u8>)>>,
+}
+
+impl Authenticator for SshSigAuthenticator {
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        //Tokens are user:timestamp:signature
+        let parts: Vec<&str> = token.split(":").collect();
+        if parts.len() != 3 {
+            return AuthResult::denied();
+        }
+        let (username, timestamp, signature) = (parts[0], parts[1], parts[2]);
+
+        let timestamp = match timestamp.parse::<u64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return AuthResult::denied(),
+        };
+        let signature = match BASE64.decode(signature) {
+            Ok(signature) => signature,
+            Err(_) => return AuthResult::denied(),
+        };
+
+        println!("Authenticating user {} with ssh signature", username);
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let expired = match timestamp.checked_add(self.max_age) {
+            Some(expires) => expires < now || timestamp > now.saturating_add(self.max_age),
+            None => true,
+        };
+        if expired {
+            println!("ssh signature of user {} has an expired timestamp {}", username, timestamp);
+            return AuthResult::denied();
+        }
+
+        let users = self.users.get();
+        let user = match users.get(username) {
+            Some(user) => user,
+            None => return AuthResult::denied(),
+        };
+
+        let message = format!("{}:{}", timestamp, self.audience);
+        let (key, raw_signature) = match verify_sshsig(&signature, message.as_bytes(), NAMESPACE) {
+            Ok(verified) => verified,
+            Err(e) => {
+                println!("Invalid ssh signature of user {}: {}", username, e);
+                return AuthResult::denied();
+            }
+        };
+        if !user.keys.contains(&key) {
+            println!("ssh signature of user {} is made with a key that is not in its authorized_keys", username);
+            return AuthResult::denied();
+        }
+
+        //Only checked for valid signatures, so random tokens can't fill the memory
+        {
+            let mut used = self.used.lock().unwrap();
+            used.retain(|(_, used_timestamp, _)| used_timestamp.saturating_add(self.max_age) >= now);
+            //Keyed by the signature itself, so the same signature in another blob is a replay too
+            if !used.insert((username.to_string(), timestamp, raw_signature)) {
+                println!("ssh signature of user {} was already used", username);
+                return AuthResult::denied();
+            }
+        }
+
+        println!("Authenticated user {} with ssh signature", username);
+        AuthResult::granted(username, username, user.groups.clone())
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading ssh_sig_auth authenticator");
+
+        //Get --ssh_sig_user_file_path argument, defaults to the users file of json_auth
+        let users_file_path = match arguments.get("ssh_sig_user_file_path").or(arguments.get("json_user_file_path")) {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--ssh_sig_user_file_path or --json_user_file_path is required for ssh_sig_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        if !Path::new(&users_file_path).exists() {
+            println!("--ssh_sig_user_file_path is not valid");
+            std::process::exit(1);
+        }
+        let users = match FileStore::load(&users_file_path, SshSigAuthenticator::parse_users) {
+            Ok(users) => users,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        //Get --ssh_sig_audience argument, required, so signatures for other clusters are rejected
+        let audience = match arguments.get("ssh_sig_audience") {
+            Some(audience) if !audience.is_empty() => audience[0].clone(),
+            _ => {
+                println!("--ssh_sig_audience is required for ssh_sig_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Get --ssh_sig_max_age argument in seconds
+        let max_age = match arguments.get("ssh_sig_max_age") {
+            Some(max_age) => match max_age[0].parse::<u64>() {
+                Ok(max_age) if max_age > 0 => max_age,
+                _ => {
+                    println!("--ssh_sig_max_age is not a valid number of seconds");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_MAX_AGE,
+        };
+
+        SshSigAuthenticator {
+            users,
+            audience,
+            max_age,
+            used: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl SshSigAuthenticator {
+    //Parse the users file, only the users with authorized_keys are kept
+    fn parse_users(content: &str) -> Result<HashMap<String, UserKeys>, String> {
+        let users: SshUsers = serde_json::from_str(content)
+            .map_err(|e| format!("Failed to deserialize users file: {}", e))?;
+
+        let mut keys: HashMap<String, UserKeys> = HashMap::new();
+        for user in users.users {
+            let mut user_keys: Vec<Vec<u8>> = vec![];
+            for line in &user.authorized_keys {
+                match parse_authorized_key(line) {
+                    Some(key) => user_keys.push(key),
+                    None => return Err(format!("Invalid authorized_keys entry of user {}: {}", user.username, line)),
+                }
+            }
+            if !user_keys.is_empty() {
+                keys.insert(
+                    user.username,
+                    UserKeys {
+                        groups: user.groups,
+                        keys: user_keys,
+                    },
+                );
+            }
+        }
+        Ok(keys)
+    }
+}
+
+//Get the key of an authorized_keys line ([options] type base64 [comment]) in ssh wire format
+fn parse_authorized_key(line: &str) -> Option<Vec<u8>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let position = fields.iter().position(|f| *f == "ssh-ed25519" || *f == "ssh-rsa")?;
+    let key = BASE64.decode(fields.get(position + 1)?).ok()?;
+
+    //The type inside the key has to match the type in front of it
+    let mut reader = SshReader::new(&key);
+    if reader.string()? != fields[position].as_bytes() {
+        return None;
+    }
+    Some(key)
+}
+
+//Verify a sshsig blob over a message, returns the public key that made the signature and the raw signature
+fn verify_sshsig(blob: &[u8], message: &[u8], namespace: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut reader = SshReader::new(blob);
+    if reader.bytes(MAGIC_PREAMBLE.len()) != Some(MAGIC_PREAMBLE) {
+        return Err(String::from("not a sshsig signature"));
+    }
+    if reader.u32() != Some(1) {
+        return Err(String::from("unsupported sshsig version"));
+    }
+    let public_key = reader.string().ok_or("invalid public key")?;
+    let signed_namespace = reader.string().ok_or("invalid namespace")?;
+    let reserved = reader.string().ok_or("invalid reserved field")?;
+    let hash_algorithm = reader.string().ok_or("invalid hash algorithm")?;
+    let signature = reader.string().ok_or("invalid signature")?;
+    if !reader.is_empty() {
+        return Err(String::from("trailing data after the signature"));
+    }
+
+    if signed_namespace != namespace.as_bytes() {
+        return Err(format!("signature is for namespace {}, not {}", String::from_utf8_lossy(signed_namespace), namespace));
+    }
+
+    let hash = match hash_algorithm {
+        b"sha512" => digest::digest(&digest::SHA512, message),
+        b"sha256" => digest::digest(&digest::SHA256, message),
+        _ => return Err(String::from("unsupported hash algorithm")),
+    };
+
+    //The data that is actually signed
+    let mut signed = MAGIC_PREAMBLE.to_vec();
+    for field in [namespace.as_bytes(), reserved, hash_algorithm, hash.as_ref()] {
+        signed.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        signed.extend_from_slice(field);
+    }
+
+    let mut key = SshReader::new(public_key);
+    let key_type = key.string().ok_or("invalid public key")?;
+    let mut reader = SshReader::new(signature);
+    let signature_type = reader.string().ok_or("invalid signature")?;
+    let signature = reader.string().ok_or("invalid signature")?;
+    if !reader.is_empty() {
+        return Err(String::from("trailing data after the signature"));
+    }
+
+    let verified = match (key_type, signature_type) {
+        (b"ssh-ed25519", b"ssh-ed25519") => {
+            let key = key.string().ok_or("invalid ed25519 key")?;
+            UnparsedPublicKey::new(&signature::ED25519, key).verify(&signed, signature)
+        }
+        (b"ssh-rsa", b"rsa-sha2-512") | (b"ssh-rsa", b"rsa-sha2-256") => {
+            let e = key.string().ok_or("invalid rsa key")?;
+            let n = key.string().ok_or("invalid rsa key")?;
+            //ssh mpints have a leading zero if the highest bit is set
+            let components = RsaPublicKeyComponents {
+                n: strip_leading_zeros(n),
+                e: strip_leading_zeros(e),
+            };
+            let parameters = if signature_type == b"rsa-sha2-512" {
+                &signature::RSA_PKCS1_2048_8192_SHA512
+            } else {
+                &signature::RSA_PKCS1_2048_8192_SHA256
+            };
+            components.verify(parameters, &signed, signature)
+        }
+        //ssh-rsa signatures use SHA1 and are not accepted
+        _ => return Err(format!("unsupported signature type {}", String::from_utf8_lossy(signature_type))),
+    };
+
+    match verified {
+        Ok(()) => Ok((public_key.to_vec(), signature.to_vec())),
+        Err(_) => Err(String::from("signature does not match")),
+    }
+}
+
+fn strip_leading_zeros(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+    &value[start..]
+}
+
+//Reads the fields of the ssh wire format
+struct SshReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        SshReader { data }
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    //A string is a u32 length followed by the bytes
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+
+    //True if everything was read
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::*;
+
+    const AUDIENCE: &str = "test-cluster";
+
+    fn push_string(out: &mut Vec<u8>, value: &[u8]) {
+        out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        out.extend_from_slice(value);
+    }
+
+    fn public_key(key: &Ed25519KeyPair) -> Vec<u8> {
+        let mut public_key = vec![];
+        push_string(&mut public_key, b"ssh-ed25519");
+        push_string(&mut public_key, key.public_key().as_ref());
+        public_key
+    }
+
+    //A sshsig blob as made by ssh-keygen -Y sign, inner_trailer is appended to the signature field
+    fn sign(key: &Ed25519KeyPair, timestamp: u64, inner_trailer: &[u8]) -> Vec<u8> {
+        let hash = digest::digest(&digest::SHA512, format!("{}:{}", timestamp, AUDIENCE).as_bytes());
+        let mut signed = MAGIC_PREAMBLE.to_vec();
+        for field in [NAMESPACE.as_bytes(), b"", b"sha512", hash.as_ref()] {
+            push_string(&mut signed, field);
+        }
+
+        let mut signature = vec![];
+        push_string(&mut signature, b"ssh-ed25519");
+        push_string(&mut signature, key.sign(&signed).as_ref());
+        signature.extend_from_slice(inner_trailer);
+
+        let mut blob = MAGIC_PREAMBLE.to_vec();
+        blob.extend_from_slice(&1u32.to_be_bytes());
+        push_string(&mut blob, &public_key(key));
+        for field in [NAMESPACE.as_bytes(), b"", b"sha512", &signature] {
+            push_string(&mut blob, field);
+        }
+        blob
+    }
+
+    fn token(timestamp: u64, blob: &[u8]) -> String {
+        format!("alice:{}:{}", timestamp, BASE64.encode(blob))
+    }
+
+    fn authenticator(name: &str, key: &Ed25519KeyPair) -> SshSigAuthenticator {
+        let users_file = std::env::temp_dir().join(format!("kubeauth-ssh-sig-test-{}-{}", std::process::id(), name));
+        let users = serde_json::json!({"users": [{
+            "username": "alice",
+            "groups": ["devs"],
+            "authorized_keys": [format!("ssh-ed25519 {} alice@laptop", BASE64.encode(public_key(key)))]
+        }]});
+        std::fs::write(&users_file, users.to_string()).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(
+            String::from("ssh_sig_user_file_path"),
+            vec![users_file.to_string_lossy().to_string()],
+        );
+        arguments.insert(String::from("ssh_sig_audience"), vec![String::from(AUDIENCE)]);
+        SshSigAuthenticator::new(arguments)
+    }
+
+    fn key() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn signatures_are_accepted_once() {
+        let key = key();
+        let ssh_sig = authenticator("once", &key);
+        let now = chrono::Utc::now().timestamp() as u64;
+        let blob = sign(&key, now, b"");
+
+        let result = ssh_sig.auth(&token(now, &blob), &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.groups, vec!["devs"]);
+
+        assert!(!ssh_sig.auth(&token(now, &blob), &[]).await.authenticated);
+
+        //The same signature with trailing data is neither valid nor a new token
+        let mut padded = blob.clone();
+        padded.push(0);
+        assert!(!ssh_sig.auth(&token(now, &padded), &[]).await.authenticated);
+    }
+
+    #[rocket::async_test]
+    async fn trailing_data_is_rejected() {
+        let key = key();
+        let ssh_sig = authenticator("trailing", &key);
+        let now = chrono::Utc::now().timestamp() as u64;
+
+        let mut blob = sign(&key, now, b"");
+        blob.extend_from_slice(b"extra");
+        assert!(!ssh_sig.auth(&token(now, &blob), &[]).await.authenticated);
+
+        let blob = sign(&key, now, b"extra");
+        assert!(!ssh_sig.auth(&token(now, &blob), &[]).await.authenticated);
+
+        //Untouched, the signature is still unused
+        assert!(ssh_sig.auth(&token(now, &sign(&key, now, b"")), &[]).await.authenticated);
+    }
+
+    #[rocket::async_test]
+    async fn timestamps_out_of_range_are_denied() {
+        let key = key();
+        let ssh_sig = authenticator("timestamps", &key);
+        let now = chrono::Utc::now().timestamp() as u64;
+
+        for timestamp in [u64::MAX, u64::MAX - DEFAULT_MAX_AGE + 1, now - 2 * DEFAULT_MAX_AGE, now + 2 * DEFAULT_MAX_AGE] {
+            let blob = sign(&key, timestamp, b"");
+            assert!(!ssh_sig.auth(&token(timestamp, &blob), &[]).await.authenticated, "{}", timestamp);
+        }
+    }
+}
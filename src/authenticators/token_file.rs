@@ -0,0 +1,244 @@
+use std::{collections::HashMap, path::Path};
+
+use subtle::ConstantTimeEq;
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::file_store::FileStore;
+
+/*
+This is a static token file authenticator.
+It reads the same csv format as the --token-auth-file flag of the kube-apiserver:
+token,user,uid,"group1,group2"
+Tokens are opaque bearer tokens, they are compared in constant time.
+*/
+
+//A single entry of the token file
+struct TokenEntry {
+    token: String,
+    username: String,
+    uid: String,
+    groups: Vec<String>,
+}
+
+//Auther struct for using a static token file
+pub struct TokenFileAuthenticator {
+    tokens: FileStore<Vec<TokenEntry>>,
+}
+
+impl Authenticator for TokenFileAuthenticator {
+    async fn auth(&self, token: &str, _audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        //Get the tokens, reloads the token file if it changed
+        let tokens = self.tokens.get();
+
+        //Compare against every entry without returning early, so the time taken does not depend on where the token matched
+        let mut matched: Option<&TokenEntry> = None;
+        for entry in tokens.iter() {
+            if bool::from(entry.token.as_bytes().ct_eq(token.as_bytes())) {
+                matched = Some(entry);
+            }
+        }
+
+        match matched {
+            Some(entry) => {
+                println!("Authenticated user {} with token file", entry.username);
+                AuthResult::granted(&entry.username, &entry.uid, entry.groups.clone())
+            }
+            None => AuthResult::denied(),
+        }
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading token_file_auth authenticator");
+
+        //Get --token_file_path argument
+        let token_file_path = match arguments.get("token_file_path") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--token_file_path is required for token_file_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        //Check if token_file_path is valid
+        if !Path::new(&token_file_path).exists() {
+            println!("--token_file_path is not valid");
+            std::process::exit(1);
+        }
+
+        let tokens = match FileStore::load(&token_file_path, TokenFileAuthenticator::parse_token_file) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        TokenFileAuthenticator { tokens }
+    }
+}
+
+impl TokenFileAuthenticator {
+    //Parse the content of a token file, every line is token,user,uid[,"group1,group2,..."]
+    fn parse_token_file(content: &str) -> Result<Vec<TokenEntry>, String> {
+        let mut entries: Vec<TokenEntry> = vec![];
+
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = TokenFileAuthenticator::parse_csv_record(line)
+                .map_err(|e| format!("Token file line {}: {}", line_number, e))?;
+
+            if record.len() < 3 {
+                return Err(format!(
+                    "Token file line {}: expected at least 3 columns (token,user,uid), got {}",
+                    line_number,
+                    record.len()
+                ));
+            }
+
+            //Same as the apiserver, lines without token are skipped
+            let token = record[0].clone();
+            if token.is_empty() {
+                println!("Token file line {}: token is empty, skipping the line", line_number);
+                continue;
+            }
+
+            //Same as the apiserver, a duplicated token overrides the earlier entry
+            if let Some(position) = entries.iter().position(|e| e.token == token) {
+                println!("Token file line {}: duplicate token, overriding the previous entry", line_number);
+                entries.remove(position);
+            }
+
+            let mut groups: Vec<String> = vec![];
+            if record.len() >= 4 {
+                for group in record[3].split(",") {
+                    let group = group.trim();
+                    if !group.is_empty() {
+                        groups.push(group.to_string());
+                    }
+                }
+            }
+
+            entries.push(TokenEntry {
+                token,
+                username: record[1].clone(),
+                uid: record[2].clone(),
+                groups,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    //Split a single csv line into its fields, fields can be quoted and quotes are escaped by doubling them
+    fn parse_csv_record(line: &str) -> Result<Vec<String>, String> {
+        let mut fields: Vec<String> = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if quoted {
+                if c == '"' {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        field.push('"');
+                    } else {
+                        quoted = false;
+                    }
+                } else {
+                    field.push(c);
+                }
+            } else if c == '"' && field.is_empty() {
+                quoted = true;
+            } else if c == ',' {
+                fields.push(field);
+                field = String::new();
+            } else {
+                field.push(c);
+            }
+        }
+
+        if quoted {
+            return Err(String::from("unterminated quoted field"));
+        }
+        fields.push(field);
+
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator(name: &str, content: &str) -> TokenFileAuthenticator {
+        let token_file = std::env::temp_dir().join(format!("kubeauth-token-file-test-{}-{}", std::process::id(), name));
+        std::fs::write(&token_file, content).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("token_file_path"), vec![token_file.to_string_lossy().to_string()]);
+        TokenFileAuthenticator::new(arguments)
+    }
+
+    #[test]
+    fn lines_are_parsed_like_the_apiserver() {
+        let entries = TokenFileAuthenticator::parse_token_file(
+            "t1,alice,1001,\"dev, ops,,\"\n\nt2,bob,\n,nobody,1003\nt1,carol,1004\n\"t,3\",\"dave \"\"d\"\"\",1005,single",
+        )
+        .unwrap();
+
+        let entries: Vec<(&str, &str, &str, Vec<String>)> = entries
+            .iter()
+            .map(|e| (e.token.as_str(), e.username.as_str(), e.uid.as_str(), e.groups.clone()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                //The uid column may be empty, the duplicate t1 overrides alice and the line without token is skipped
+                ("t2", "bob", "", vec![]),
+                ("t1", "carol", "1004", vec![]),
+                ("t,3", "dave \"d\"", "1005", vec![String::from("single")]),
+            ]
+        );
+
+        let entries = TokenFileAuthenticator::parse_token_file("t1,alice,1001,\"dev, ops,,\"").unwrap();
+        assert_eq!(entries[0].groups, vec!["dev", "ops"]);
+    }
+
+    #[test]
+    fn malformed_lines_fail_the_file() {
+        let too_short = TokenFileAuthenticator::parse_token_file("t1,alice,1001\nt2,bob");
+        assert_eq!(
+            too_short.err().unwrap(),
+            "Token file line 2: expected at least 3 columns (token,user,uid), got 2"
+        );
+
+        let unterminated = TokenFileAuthenticator::parse_token_file("t1,alice,1001,\"dev,ops");
+        assert_eq!(unterminated.err().unwrap(), "Token file line 1: unterminated quoted field");
+    }
+
+    #[rocket::async_test]
+    async fn matching_tokens_are_granted() {
+        let token_file = authenticator("match", "31ada4fd,alice,1001,\"dev,ops\"\n6a9d7fa1,bob,\n");
+
+        let result = token_file.auth("31ada4fd", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.uid, "1001");
+        assert_eq!(result.groups, vec!["dev", "ops"]);
+
+        //Without uid the username is the uid
+        assert_eq!(token_file.auth("6a9d7fa1", &[]).await.uid, "bob");
+
+        for token in ["", "31ada4f", "31ada4fd ", "alice:31ada4fd", "unknown"] {
+            assert!(!token_file.auth(token, &[]).await.authenticated, "{:?}", token);
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use qrcode::{render::unicode, QrCode};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use super::file_store::FileStore;
+use super::json::JsonAuthenticator;
+
+/*
+TOTP second factor (RFC 6238) for the user:password authenticators.
+The code is appended to the password, either as user:password:123456 or as user:password123456.
+Users with a TOTP secret have to send a code, the secret is read from the totp_secret of the
+json user or from a secrets file with username:secret lines, that works for every password authenticator.
+The secret is looked up by the username the password authenticator granted, not by the name in the token,
+so variants like ALICE or CORP\alice the backend accepts for alice need the code of alice as well.
+Codes are SHA1, 6 digits and 30 seconds, which is what all authenticator apps support.
+A code can only be used once, so an intercepted token can't be replayed to kubeauth. The API server
+caches the result of a token for --authentication-token-webhook-cache-ttl, within that time it
+accepts the same token again without asking kubeauth.
+exec_auth, script_auth and wasm_auth get the whole token, so they must not check passwords of users with a secret.
+*/
+
+//Digits of a code
+const DIGITS: usize = 6;
+
+//Seconds a code is valid
+const PERIOD: u64 = 30;
+
+//Default number of periods before and after the current one that are accepted
+const DEFAULT_WINDOW: u64 = 1;
+
+//Alphabet of the base32 encoded secrets (RFC 4648)
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+//The authenticators that check passwords, only their tokens carry a code
+pub const PASSWORD_AUTHENTICATORS: &[&str] = &["json_auth", "ldap_auth", "htpasswd_auth", "radius_auth", "http_auth"];
+
+//Credentials a password authenticator is asked with, code is the code split off the password
+pub struct Credentials {
+    pub token: String,
+    pub code: Option<String>,
+}
+
+//Checks the codes, shared by all password authenticators
+pub struct TotpChecker {
+    //username -> base32 secret
+    secrets: Option<FileStore<HashMap<String, String>>>,
+    window: u64,
+    //Users without secret are denied by the password authenticators
+    required: bool,
+    //username -> last used period, codes of this or earlier periods are rejected
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl TotpChecker {
+    pub fn new(arguments: &HashMap<String, Vec<String>>) -> Self {
+        //Get --totp_secrets_file argument, optional
+        let secrets = match arguments.get("totp_secrets_file") {
+            Some(path) if !path.is_empty() => {
+                if !Path::new(&path[0]).exists() {
+                    println!("--totp_secrets_file is not valid");
+                    std::process::exit(1);
+                }
+                match FileStore::load(&path[0], parse_secrets) {
+                    Ok(secrets) => Some(secrets),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        //Get --totp_window argument, in periods of 30 seconds
+        let window = match arguments.get("totp_window") {
+            Some(window) => match window[0].parse::<u64>() {
+                Ok(window) if window <= 10 => window,
+                _ => {
+                    println!("--totp_window is not a valid number of periods (0-10)");
+                    std::process::exit(1);
+                }
+            },
+            None => DEFAULT_WINDOW,
+        };
+
+        //Get --totp_required argument, default to false
+        let required = match arguments.get("totp_required") {
+            Some(required) => required[0] == "true",
+            None => false,
+        };
+
+        TotpChecker {
+            secrets,
+            window,
+            required,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //Split a user:password token into the credentials the password authenticators are asked with, in this order
+    //Which user the token belongs to is only known once an authenticator granted it, ALICE or CORP\alice may be alice,
+    //so the password is tried with and without the code and the code is checked by verify with the granted username
+    pub fn split(&self, token: &str, json: Option<&JsonAuthenticator>) -> Vec<Credentials> {
+        let whole = Credentials {
+            token: token.to_string(),
+            code: None,
+        };
+
+        let (username, password) = match token.split_once(":") {
+            Some(split) => split,
+            None => return vec![whole],
+        };
+        let (password, code) = match split_code(password) {
+            Some(split) => split,
+            None => return vec![whole],
+        };
+
+        //Without any secret there is no code, passwords ending with 6 digits are not tried twice
+        let secrets = self.secrets.is_some() || json.is_some_and(|json| json.has_totp_secrets());
+        if !secrets && !self.required {
+            return vec![whole];
+        }
+
+        let with_code = Credentials {
+            token: format!("{}:{}", username, password),
+            code: Some(code.to_string()),
+        };
+
+        //A user that is known to have a secret has to send a code, so the whole token is not tried
+        //Otherwise the password is more likely to be the whole password, the code is tried second
+        if self.secret(username, json).is_some() {
+            vec![with_code]
+        } else {
+            vec![whole, with_code]
+        }
+    }
+
+    //Check the code after a password authenticator granted access to username, the username the authenticator returned
+    //Users with a secret need a valid code, users without are denied if --totp_required is set
+    pub fn verify(&self, username: &str, code: Option<&str>, json: Option<&JsonAuthenticator>) -> bool {
+        let secret = match self.secret(username, json) {
+            Some(secret) => match base32_decode(&secret) {
+                Some(secret) => secret,
+                None => {
+                    println!("TOTP secret of user {} is not valid base32", username);
+                    return false;
+                }
+            },
+            None if self.required => {
+                println!("User {} has no TOTP secret, but --totp_required is set", username);
+                return false;
+            }
+            None => return true,
+        };
+
+        let code = match code {
+            Some(code) => code,
+            None => {
+                println!("User {} sent no TOTP code", username);
+                return false;
+            }
+        };
+
+        let now = chrono::Utc::now().timestamp() as u64 / PERIOD;
+        let mut used = self.used.lock().unwrap();
+        let last_used = used.get(username).copied();
+
+        for period in now.saturating_sub(self.window)..=now + self.window {
+            if !bool::from(hotp(&secret, period).as_bytes().ct_eq(code.as_bytes())) {
+                continue;
+            }
+            if last_used.is_some_and(|last| period <= last) {
+                println!("TOTP code of user {} was already used", username);
+                return false;
+            }
+            used.insert(username.to_string(), period);
+            return true;
+        }
+
+        println!("Invalid TOTP code for user {}", username);
+        false
+    }
+
+    //The secret of a user, from the secrets file or the totp_secret of the json user
+    fn secret(&self, username: &str, json: Option<&JsonAuthenticator>) -> Option<String> {
+        self.secrets
+            .as_ref()
+            .and_then(|secrets| secrets.get().get(username).cloned())
+            .or_else(|| json.and_then(|json| json.totp_secret(username)))
+    }
+}
+
+//Split the code off a password, password:123456 or password123456
+fn split_code(password: &str) -> Option<(&str, &str)> {
+    let is_code = |code: &str| code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit());
+
+    if let Some((password, code)) = password.rsplit_once(":") {
+        if is_code(code) {
+            return Some((password, code));
+        }
+    }
+    if password.len() > DIGITS && password.is_char_boundary(password.len() - DIGITS) {
+        let (password, code) = password.split_at(password.len() - DIGITS);
+        if is_code(code) {
+            return Some((password, code));
+        }
+    }
+    None
+}
+
+//Parse a secrets file, every line is username:base32 secret
+fn parse_secrets(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut secrets: HashMap<String, String> = HashMap::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+
+        match line.split_once(":") {
+            Some((username, secret)) if !username.is_empty() && base32_decode(secret).is_some() => {
+                secrets.insert(username.to_string(), secret.to_string());
+            }
+            _ => return Err(format!("TOTP secrets file line {}: expected username:base32 secret", index + 1)),
+        }
+    }
+
+    Ok(secrets)
+}
+
+//HOTP code of a counter (RFC 4226)
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let mac = hmac_sha1(secret, &counter.to_be_bytes());
+    let offset = (mac[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let inner = inner.finalize();
+
+    let mut outer = Sha1::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner);
+
+    let mut mac = [0u8; 20];
+    mac.copy_from_slice(&outer.finalize());
+    mac
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            encoded.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 0x1f) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+//Decode base32, case insensitive, spaces and padding are ignored like in most authenticator apps
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded: Vec<u8> = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.chars().filter(|c| *c != ' ' && *c != '=') {
+        let value = BASE32_ALPHABET.iter().position(|a| *a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            decoded.push((buffer >> (bits - 8)) as u8);
+            bits -= 8;
+        }
+    }
+    if decoded.is_empty() {
+        return None;
+    }
+    Some(decoded)
+}
+
+//Percent encode a part of the otpauth uri
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+//Enroll a user, used by the totp enroll subcommand
+//Creates a secret and prints it as otpauth uri and QR code, with --totp_secrets_file it is also saved there
+pub fn enroll(arguments: &HashMap<String, Vec<String>>) {
+    //Get --username argument, required
+    let username = match arguments.get("username") {
+        Some(username) if !username.is_empty() && !username[0].contains(":") => username[0].clone(),
+        _ => {
+            println!("--username is required and must not contain :");
+            std::process::exit(1);
+        }
+    };
+
+    //Get --totp_issuer argument, shown in the authenticator app
+    let issuer = arguments
+        .get("totp_issuer")
+        .map(|issuer| issuer[0].clone())
+        .unwrap_or(String::from("kubeauth"));
+
+    //160 bits, as recommended by RFC 4226
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    let secret = base32_encode(&secret);
+
+    let uri = format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        uri_encode(&issuer),
+        uri_encode(&username),
+        secret,
+        uri_encode(&issuer),
+        DIGITS,
+        PERIOD
+    );
+
+    match QrCode::new(uri.as_bytes()) {
+        Ok(code) => {
+            let image = code
+                .render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build();
+            println!("{}", image);
+        }
+        Err(e) => println!("Failed to create QR code: {}", e),
+    }
+    println!("{}", uri);
+
+    match arguments.get("totp_secrets_file") {
+        Some(path) if !path.is_empty() => {
+            if let Err(e) = save_secret(&path[0], &username, &secret) {
+                println!("Failed to save the secret to {}: {}", path[0], e);
+                std::process::exit(1);
+            }
+            println!("Saved the secret of user {} to {}", username, path[0]);
+        }
+        _ => {
+            println!("Add this to the user in the users file:");
+            println!("\"totp_secret\": \"{}\"", secret);
+        }
+    }
+}
+
+//Add or replace the secret of a user in the secrets file, the file is only readable by its owner
+fn save_secret(path: &str, username: &str, secret: &str) -> Result<(), String> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<String> = content
+        .lines()
+        .filter(|line| line.split_once(":").map(|(u, _)| u.trim()) != Some(username))
+        .map(|line| line.to_string())
+        .collect();
+    lines.push(format!("{}:{}", username, secret));
+
+    //Written to a new file and renamed, so kubeauth never reads a half written file
+    let temp = format!("{}.tmp", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp).map_err(|e| e.to_string())?;
+    file.write_all((lines.join("\n") + "\n").as_bytes())
+        .map_err(|e| e.to_string())?;
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The SHA1 secret of the RFC 6238 test vectors, 12345678901234567890 in base32
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn checker(name: &str, secrets: &str, required: bool) -> TotpChecker {
+        let secrets_file = std::env::temp_dir().join(format!("kubeauth-totp-test-{}-{}", std::process::id(), name));
+        fs::write(&secrets_file, secrets).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("totp_secrets_file"), vec![secrets_file.to_string_lossy().to_string()]);
+        arguments.insert(String::from("totp_required"), vec![required.to_string()]);
+        TotpChecker::new(&arguments)
+    }
+
+    fn current_code() -> String {
+        hotp(&base32_decode(SECRET).unwrap(), chrono::Utc::now().timestamp() as u64 / PERIOD)
+    }
+
+    fn tokens(credentials: &[Credentials]) -> Vec<(&str, Option<&str>)> {
+        credentials.iter().map(|c| (c.token.as_str(), c.code.as_deref())).collect()
+    }
+
+    #[test]
+    fn rfc_6238_vectors() {
+        //The RFC lists 8 digits, the 6 digit codes are the last 6 of them
+        let secret = base32_decode(SECRET).unwrap();
+        assert_eq!(secret, b"12345678901234567890");
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(hotp(&secret, time / PERIOD), code, "{}", time);
+        }
+        assert_eq!(base32_encode(&secret), SECRET);
+    }
+
+    #[test]
+    fn codes_are_split_off_the_password() {
+        assert_eq!(split_code("password:123456"), Some(("password", "123456")));
+        assert_eq!(split_code("password123456"), Some(("password", "123456")));
+        assert_eq!(split_code("pass:word:123456"), Some(("pass:word", "123456")));
+        assert_eq!(split_code("password:12345"), None);
+        assert_eq!(split_code("password12345"), None);
+        assert_eq!(split_code("123456"), None);
+        assert_eq!(split_code("passwörd12345a"), None);
+    }
+
+    #[test]
+    fn codes_are_only_used_once() {
+        let totp = checker("reuse", &format!("alice:{}\n", SECRET), false);
+        let code = current_code();
+
+        assert!(totp.verify("alice", Some(&code), None));
+        assert!(!totp.verify("alice", Some(&code), None));
+        //A code outside of the window
+        let later = hotp(&base32_decode(SECRET).unwrap(), chrono::Utc::now().timestamp() as u64 / PERIOD + 5);
+        assert!(!totp.verify("alice", Some(&later), None));
+        assert!(!totp.verify("alice", None, None));
+    }
+
+    #[test]
+    fn users_without_secret_need_no_code_unless_required() {
+        let totp = checker("optional", &format!("alice:{}\n", SECRET), false);
+        assert!(totp.verify("bob", None, None));
+        assert_eq!(tokens(&totp.split("bob:password", None)), vec![("bob:password", None)]);
+
+        let totp = checker("required", &format!("alice:{}\n", SECRET), true);
+        assert!(!totp.verify("bob", None, None));
+        assert!(!totp.verify("bob", Some(&current_code()), None));
+        assert!(totp.verify("alice", Some(&current_code()), None));
+    }
+
+    #[test]
+    fn the_code_is_checked_for_the_granted_user() {
+        let totp = checker("variants", &format!("alice:{}\n", SECRET), false);
+
+        //alice is known to have a secret, only the password without code is tried
+        assert_eq!(
+            tokens(&totp.split("alice:password:123456", None)),
+            vec![("alice:password", Some("123456"))]
+        );
+
+        //A backend may grant ALICE, CORP\alice or alice@corp.example as alice, the name in the token has no secret
+        for login in ["ALICE", "CORP\\alice", "alice@corp.example"] {
+            let token = format!("{}:password123456", login);
+            let without_code = format!("{}:password", login);
+            assert_eq!(
+                tokens(&totp.split(&token, None)),
+                vec![(token.as_str(), None), (without_code.as_str(), Some("123456"))]
+            );
+
+            //Without code, the whole token is the only try, granted as alice it is still denied
+            assert_eq!(tokens(&totp.split(&format!("{}:password", login), None)).len(), 1);
+            assert!(!totp.verify("alice", None, None));
+        }
+
+        assert!(totp.verify("alice", Some(&current_code()), None));
+    }
+}
@@ -0,0 +1,579 @@
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasmtime::{Caller, Config, Engine, Extern, InstancePre, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+use wasmtime_wasi::{preview1::WasiP1Ctx, WasiCtxBuilder};
+
+use super::authenticator::{AuthResult, Authenticator};
+
+/*
+This is a host for authenticators written as WebAssembly modules, so they can be shipped without rebuilding kubeauth.
+Every token runs in a new instance of the module with limited fuel, time and memory.
+Modules only get the host functions they are granted: the config blob, logging and stderr.
+WASI is linked without files, environment variables or arguments, so modules built for wasm32-wasip1 work.
+
+The interface of a module:
+  export memory
+  export kubeauth_alloc(len: i32) -> i32, returns a buffer of len bytes for the request
+  export kubeauth_auth(ptr: i32, len: i32) -> i64, gets {"token": "...", "audiences": [...]} as JSON and
+      returns the result JSON as ptr << 32 | len, the result is the same as the output of exec_auth
+  import kubeauth.config_size() -> i32 and kubeauth.config_read(ptr: i32, len: i32) -> i32 with the config capability
+  import kubeauth.log(ptr: i32, len: i32) with the log capability
+*/
+
+//Default fuel of a single authentication, roughly the number of wasm instructions
+const DEFAULT_FUEL: u64 = 100_000_000;
+
+//Default milliseconds a single authentication may run
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+//Default maximum memory of an instance in MiB
+const DEFAULT_MAX_MEMORY_MB: usize = 64;
+
+//The epoch of the engine is increased this often, the timeouts are multiples of it
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+//Longer results are rejected
+const MAX_OUTPUT: usize = 1024 * 1024;
+
+//Longer log lines are cut off
+const MAX_LOG_LINE: usize = 4096;
+
+//The wasm config file
+#[derive(Deserialize)]
+struct WasmConfig {
+    modules: Vec<ModuleConfig>,
+}
+
+//The config of a single module
+#[derive(Deserialize)]
+struct ModuleConfig {
+    //Used in the logs
+    name: String,
+    //A .wasm or .wat file
+    path: String,
+    //Host capabilities the module is granted: config, log, stderr
+    #[serde(default)]
+    capabilities: Vec<String>,
+    //The config blob, a JSON value or the content of a file
+    config: Option<Value>,
+    config_file: Option<String>,
+    #[serde(default = "default_fuel")]
+    fuel: u64,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default = "default_max_memory_mb")]
+    max_memory_mb: usize,
+}
+
+fn default_fuel() -> u64 {
+    DEFAULT_FUEL
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn default_max_memory_mb() -> usize {
+    DEFAULT_MAX_MEMORY_MB
+}
+
+//Passed to kubeauth_auth
+#[derive(Serialize)]
+struct WasmRequest<'a> {
+    token: &'a str,
+    audiences: &'a [String],
+}
+
+//Returned by kubeauth_auth, only authenticated is required
+#[derive(Deserialize)]
+struct WasmResponse {
+    #[serde(default)]
+    authenticated: bool,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    uid: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    extra: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    audiences: Vec<String>,
+}
+
+//The state of an instance
+struct HostState {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+    config: Arc<Vec<u8>>,
+    name: Arc<String>,
+}
+
+//A compiled module linked with its host functions
+struct WasmModule {
+    name: Arc<String>,
+    module: InstancePre<HostState>,
+    config: Arc<Vec<u8>>,
+    stderr: bool,
+    fuel: u64,
+    epoch_ticks: u64,
+    max_memory: usize,
+}
+
+impl WasmModule {
+    //Run the module for a request in a new instance
+    fn run(&self, engine: &Engine, input: &[u8]) -> Result<WasmResponse, String> {
+        let mut wasi = WasiCtxBuilder::new();
+        if self.stderr {
+            wasi.inherit_stderr();
+        }
+        let state = HostState {
+            wasi: wasi.build_p1(),
+            limits: StoreLimitsBuilder::new().memory_size(self.max_memory).instances(1).build(),
+            config: self.config.clone(),
+            name: self.name.clone(),
+        };
+
+        let mut store = Store::new(engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+        store.set_epoch_deadline(self.epoch_ticks);
+
+        let instance = self.module.instantiate(&mut store).map_err(describe)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or("module exports no memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "kubeauth_alloc")
+            .map_err(|e| e.to_string())?;
+        let auth = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "kubeauth_auth")
+            .map_err(|e| e.to_string())?;
+
+        let ptr = alloc.call(&mut store, input.len() as i32).map_err(describe)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|_| String::from("kubeauth_alloc returned an invalid buffer"))?;
+
+        let packed = auth.call(&mut store, (ptr, input.len() as i32)).map_err(describe)? as u64;
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        if len > MAX_OUTPUT {
+            return Err(format!("result is longer than {} bytes", MAX_OUTPUT));
+        }
+        let mut output = vec![0u8; len];
+        memory
+            .read(&store, ptr, &mut output)
+            .map_err(|_| String::from("kubeauth_auth returned an invalid buffer"))?;
+
+        serde_json::from_slice(&output).map_err(|e| format!("invalid result: {}", e))
+    }
+}
+
+//Auther struct for WebAssembly modules
+pub struct WasmAuthenticator {
+    engine: Engine,
+    modules: Vec<Arc<WasmModule>>,
+}
+
+impl Authenticator for WasmAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let request = WasmRequest { token, audiences };
+        let input = match serde_json::to_vec(&request) {
+            Ok(input) => Arc::new(input),
+            Err(_) => return AuthResult::denied(),
+        };
+
+        //Error of a failed module, only returned if no module authenticates the token
+        let mut error: Option<String> = None;
+
+        //The modules are tried in order until one authenticates the token
+        for module in &self.modules {
+            let engine = self.engine.clone();
+            let task_module = module.clone();
+            let task_input = input.clone();
+            //Running wasm blocks the thread until the module returns or runs out of fuel or time
+            let response = tokio::task::spawn_blocking(move || task_module.run(&engine, &task_input))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|response| response);
+
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    println!("wasm_auth module {} failed: {}", module.name, e);
+                    error = Some(format!("wasm_auth: module {} failed", module.name));
+                    continue;
+                }
+            };
+
+            if !response.authenticated {
+                continue;
+            }
+            if response.username.is_empty() {
+                println!("wasm_auth module {} returned no username", module.name);
+                continue;
+            }
+
+            println!("Authenticated user {} with wasm module {}", response.username, module.name);
+            let mut result = AuthResult::granted(&response.username, &response.uid, response.groups);
+            result.extra = response.extra;
+            result.audiences = response.audiences;
+            return result;
+        }
+
+        //A module that traps or runs out of fuel or time is no denial, the apiserver must not cache it
+        match error {
+            Some(error) => AuthResult::failed(&error),
+            None => AuthResult::denied(),
+        }
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading wasm_auth authenticator");
+
+        //Get --wasm_config_file argument, required
+        let config_file = match arguments.get("wasm_config_file") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--wasm_config_file is required for wasm_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+
+        let config: WasmConfig = match fs::read_to_string(&config_file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to load --wasm_config_file {}: {}", config_file, e);
+                std::process::exit(1);
+            }
+        };
+
+        if config.modules.is_empty() {
+            println!("--wasm_config_file {} has no modules", config_file);
+            std::process::exit(1);
+        }
+
+        let mut engine_config = Config::new();
+        engine_config.consume_fuel(true);
+        engine_config.epoch_interruption(true);
+        let engine = match Engine::new(&engine_config) {
+            Ok(engine) => engine,
+            Err(e) => {
+                println!("Failed to create wasm engine: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let mut modules: Vec<Arc<WasmModule>> = vec![];
+        for module_config in config.modules {
+            let name = module_config.name.clone();
+            match WasmAuthenticator::load_module(&engine, module_config) {
+                Ok(module) => {
+                    println!("Loaded wasm module {}", name);
+                    modules.push(Arc::new(module));
+                }
+                Err(e) => {
+                    println!("Failed to load wasm module {}: {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        //The timeouts of the instances are checked against the epoch of the engine
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        });
+
+        WasmAuthenticator { engine, modules }
+    }
+}
+
+impl WasmAuthenticator {
+    //Compile a module and link the host functions it is granted
+    fn load_module(engine: &Engine, config: ModuleConfig) -> Result<WasmModule, String> {
+        //Module::from_file accepts the binary and the text format
+        let module = Module::from_file(engine, &config.path).map_err(|e| format!("{}: {}", config.path, e))?;
+
+        for capability in &config.capabilities {
+            if !["config", "log", "stderr"].contains(&capability.as_str()) {
+                return Err(format!("unknown capability {}", capability));
+            }
+        }
+        let granted = |capability: &str| config.capabilities.iter().any(|c| c == capability);
+
+        let blob = match (&config.config, &config.config_file) {
+            (Some(_), Some(_)) => return Err(String::from("config and config_file can't be used together")),
+            (Some(value), None) => value.to_string().into_bytes(),
+            (None, Some(path)) => fs::read(path).map_err(|e| format!("failed to read config_file {}: {}", path, e))?,
+            (None, None) => vec![],
+        };
+        if !blob.is_empty() && !granted("config") {
+            return Err(String::from("module has a config but not the config capability"));
+        }
+
+        let mut linker: Linker<HostState> = Linker::new(engine);
+        wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |state| &mut state.wasi).map_err(|e| e.to_string())?;
+
+        if granted("config") {
+            linker
+                .func_wrap("kubeauth", "config_size", |caller: Caller<'_, HostState>| {
+                    caller.data().config.len() as i32
+                })
+                .map_err(|e| e.to_string())?;
+            //Copies up to len bytes of the config to ptr, returns the number of bytes copied or -1
+            linker
+                .func_wrap("kubeauth", "config_read", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                    let memory = match guest_memory(&mut caller) {
+                        Some(memory) => memory,
+                        None => return -1,
+                    };
+                    let config = caller.data().config.clone();
+                    let len = (len.max(0) as usize).min(config.len());
+                    match memory.write(&mut caller, ptr as u32 as usize, &config[..len]) {
+                        Ok(()) => len as i32,
+                        Err(_) => -1,
+                    }
+                })
+                .map_err(|e| e.to_string())?;
+        }
+
+        if granted("log") {
+            linker
+                .func_wrap("kubeauth", "log", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                    let memory = match guest_memory(&mut caller) {
+                        Some(memory) => memory,
+                        None => return,
+                    };
+                    let mut line = vec![0u8; (len.max(0) as usize).min(MAX_LOG_LINE)];
+                    if memory.read(&caller, ptr as u32 as usize, &mut line).is_ok() {
+                        println!("wasm_auth module {}: {}", caller.data().name, String::from_utf8_lossy(&line));
+                    }
+                })
+                .map_err(|e| e.to_string())?;
+        }
+
+        //Fails if the module imports host functions it is not granted
+        let module = linker.instantiate_pre(&module).map_err(|e| format!("{:#}", e))?;
+
+        let epoch_ticks = (config.timeout_ms / EPOCH_TICK.as_millis() as u64).max(1);
+
+        Ok(WasmModule {
+            name: Arc::new(config.name),
+            module,
+            config: Arc::new(blob),
+            stderr: granted("stderr"),
+            fuel: config.fuel,
+            epoch_ticks,
+            max_memory: config.max_memory_mb * 1024 * 1024,
+        })
+    }
+}
+
+fn guest_memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Some(memory),
+        _ => None,
+    }
+}
+
+//Describe the traps of the limits, other errors as they are
+fn describe(error: wasmtime::Error) -> String {
+    match error.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => String::from("ran out of fuel"),
+        Some(Trap::Interrupt) => String::from("timed out"),
+        _ => format!("{:#}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    //A module that answers every request with the result JSON
+    fn respond(result: &str) -> String {
+        format!(
+            r#"(module
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "{}")
+  (func (export "kubeauth_alloc") (param i32) (result i32) i32.const 0)
+  (func (export "kubeauth_auth") (param i32 i32) (result i64) i64.const {}))"#,
+            result.replace('"', "\\\""),
+            (1024u64 << 32) | result.len() as u64
+        )
+    }
+
+    //A module whose kubeauth_auth runs body and returns an empty result
+    fn run(body: &str) -> String {
+        format!(
+            r#"(module
+  (memory (export "memory") 1)
+  (func (export "kubeauth_alloc") (param i32) (result i32) i32.const 0)
+  (func (export "kubeauth_auth") (param i32 i32) (result i64) {} i64.const 0))"#,
+            body
+        )
+    }
+
+    //Write the modules as .wat files, modules are (name, wat, extra module config)
+    fn module_configs(test: &str, modules: &[(&str, String, Value)]) -> Vec<Value> {
+        modules
+            .iter()
+            .map(|(name, wat, extra)| {
+                let path = std::env::temp_dir().join(format!("kubeauth-wasm-test-{}-{}-{}.wat", std::process::id(), test, name));
+                fs::write(&path, wat).unwrap();
+                let mut config = json!({"name": name, "path": path.to_string_lossy()});
+                for (key, value) in extra.as_object().unwrap() {
+                    config[key] = value.clone();
+                }
+                config
+            })
+            .collect()
+    }
+
+    fn authenticator(test: &str, modules: &[(&str, String, Value)]) -> WasmAuthenticator {
+        let config_file = std::env::temp_dir().join(format!("kubeauth-wasm-test-{}-{}.json", std::process::id(), test));
+        fs::write(&config_file, json!({"modules": module_configs(test, modules)}).to_string()).unwrap();
+        let mut arguments: HashMap<String, Vec<String>> = HashMap::new();
+        arguments.insert(String::from("wasm_config_file"), vec![config_file.to_string_lossy().to_string()]);
+        WasmAuthenticator::new(arguments)
+    }
+
+    #[rocket::async_test]
+    async fn the_result_of_a_granting_module_is_mapped() {
+        let wasm = authenticator(
+            "grant",
+            &[
+                ("deny", respond(r#"{"authenticated": false}"#), json!({})),
+                ("nouser", respond(r#"{"authenticated": true}"#), json!({})),
+                (
+                    "grant",
+                    respond(r#"{"authenticated": true, "username": "alice", "uid": "1001", "groups": ["dev"], "extra": {"team": ["a"]}, "audiences": ["api"]}"#),
+                    json!({}),
+                ),
+            ],
+        );
+
+        let result = wasm.auth("token", &[String::from("api")]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.uid, "1001");
+        assert_eq!(result.groups, vec!["dev"]);
+        assert_eq!(result.extra["team"], vec!["a"]);
+        assert_eq!(result.audiences, vec!["api"]);
+    }
+
+    #[rocket::async_test]
+    async fn endless_loops_are_stopped() {
+        let wasm = authenticator(
+            "loop",
+            &[
+                ("fuel", run("(loop br 0)"), json!({"fuel": 1_000_000, "timeout_ms": 60_000})),
+                ("time", run("(loop br 0)"), json!({"fuel": u64::MAX, "timeout_ms": 50})),
+            ],
+        );
+
+        let started = std::time::Instant::now();
+        let result = wasm.auth("token", &[]).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!result.authenticated);
+        assert_eq!(result.error.as_deref(), Some("wasm_auth: module time failed"));
+
+        let module = &wasm.modules[0];
+        assert_eq!(module.run(&wasm.engine, b"{}").err().unwrap(), "ran out of fuel");
+        let module = &wasm.modules[1];
+        assert_eq!(module.run(&wasm.engine, b"{}").err().unwrap(), "timed out");
+    }
+
+    #[rocket::async_test]
+    async fn memory_is_limited() {
+        //Traps if growing to 128 MiB works, returns an empty result otherwise
+        let wasm = authenticator(
+            "memory",
+            &[("grow", run("(if (i32.ne (memory.grow (i32.const 2048)) (i32.const -1)) (then unreachable))"), json!({"max_memory_mb": 1}))],
+        );
+        assert_eq!(
+            wasm.modules[0].run(&wasm.engine, b"{}").err().unwrap(),
+            "invalid result: EOF while parsing a value at line 1 column 0"
+        );
+
+        //With enough memory the grow works and the module traps
+        let wasm = authenticator(
+            "memory-granted",
+            &[("grow", run("(if (i32.ne (memory.grow (i32.const 2048)) (i32.const -1)) (then unreachable))"), json!({"max_memory_mb": 256}))],
+        );
+        assert!(wasm.modules[0].run(&wasm.engine, b"{}").err().unwrap().contains("unreachable"));
+    }
+
+    #[rocket::async_test]
+    async fn a_failed_module_does_not_hide_a_grant() {
+        let wasm = authenticator(
+            "failed",
+            &[
+                ("trap", run("unreachable"), json!({})),
+                ("grant", respond(r#"{"authenticated": true, "username": "alice"}"#), json!({})),
+            ],
+        );
+        let result = wasm.auth("token", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+
+        let wasm = authenticator(
+            "failed-deny",
+            &[
+                ("trap", run("unreachable"), json!({})),
+                ("deny", respond(r#"{"authenticated": false}"#), json!({})),
+            ],
+        );
+        let result = wasm.auth("token", &[]).await;
+        assert!(!result.authenticated);
+        assert_eq!(result.error.as_deref(), Some("wasm_auth: module trap failed"));
+    }
+
+    #[test]
+    fn the_config_needs_the_capability() {
+        //Returns the config as result
+        let wat = r#"(module
+  (import "kubeauth" "config_size" (func $size (result i32)))
+  (import "kubeauth" "config_read" (func $read (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "kubeauth_alloc") (param i32) (result i32) i32.const 0)
+  (func (export "kubeauth_auth") (param i32 i32) (result i64)
+    (i64.or
+      (i64.const 8589934592)
+      (i64.extend_i32_u (call $read (i32.const 2) (call $size))))))"#
+            .to_string();
+        let config = json!({"authenticated": true, "username": "from-config"});
+
+        let mut engine_config = Config::new();
+        engine_config.consume_fuel(true);
+        engine_config.epoch_interruption(true);
+        let engine = Engine::new(&engine_config).unwrap();
+        let load = |test: &str, extra: Value| {
+            let module = module_configs(test, &[("config", wat.clone(), extra)]).remove(0);
+            WasmAuthenticator::load_module(&engine, serde_json::from_value(module).unwrap())
+        };
+
+        let module = load("config-granted", json!({"capabilities": ["config"], "config": config})).unwrap();
+        let response = module.run(&engine, b"{}").unwrap();
+        assert!(response.authenticated);
+        assert_eq!(response.username, "from-config");
+
+        //The module imports the config functions without the capability
+        let error = load("config-missing", json!({})).err().unwrap();
+        assert!(error.contains("config_size") || error.contains("config_read"), "{}", error);
+        let error = load("config-only-log", json!({"capabilities": ["log"], "config": config})).err().unwrap();
+        assert_eq!(error, "module has a config but not the config capability");
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use super::authenticator::{AuthResult, Authenticator};
+use super::file_store::FileStore;
+use super::kubeconfig::{self, KubeEndpoint};
+use crate::token_review::{TokenRequest, TokenReviewRequestSpec, TokenReviewResponse};
+
+/*
+This is a webhook authenticator, it forwards the token as TokenReview to another token webhook.
+It allows to put kubeauth in front of an existing webhook authenticator and move users over step by step.
+The upstream webhook is configured with a kubeconfig, the same way the kube-apiserver is configured with
+--authentication-token-webhook-config-file. The server of the cluster is the url of the webhook.
+*/
+
+//Timeout of the requests to the upstream webhook
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//Auther struct for delegating to an upstream token webhook
+pub struct WebhookAuthenticator {
+    endpoint: KubeEndpoint,
+    //upstream group -> kubeauth groups, groups without mapping are kept as they are
+    group_mapping: Option<FileStore<HashMap<String, Vec<String>>>>,
+}
+
+impl Authenticator for WebhookAuthenticator {
+    async fn auth(&self, token: &str, audiences: &[String]) -> AuthResult {
+        if token.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let review = TokenRequest {
+            apiVersion: String::from("authentication.k8s.io/v1"),
+            kind: String::from("TokenReview"),
+            spec: TokenReviewRequestSpec {
+                token: token.to_string(),
+                audiences: audiences.to_vec(),
+            },
+        };
+
+        //An upstream that can't be reached or answers with an error is no denial, the apiserver must not cache it
+        let response = match self.review(&review).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Upstream webhook failed: {}", e);
+                return AuthResult::failed("webhook_auth: upstream webhook unavailable");
+            }
+        };
+
+        let status = response.status;
+        if !status.error.is_empty() {
+            println!("Upstream webhook could not check the token: {}", status.error);
+            return AuthResult::failed("webhook_auth: upstream webhook error");
+        }
+        if !status.authenticated || status.user.username.is_empty() {
+            return AuthResult::denied();
+        }
+
+        let groups = self.map_groups(status.user.groups);
+
+        println!("Authenticated user {} with upstream webhook", status.user.username);
+        let mut result = AuthResult::granted(&status.user.username, &status.user.uid, groups);
+        //An upstream without audiences accepts the token for the audiences of the apiserver, same as kubeauth
+        result.audiences = status.audiences;
+        result.extra = status.user.extra;
+        result
+    }
+
+    fn new(arguments: HashMap<String, Vec<String>>) -> Self {
+        println!("Loading webhook_auth authenticator");
+
+        //Get --webhook_config_file argument, required
+        let config_file = match arguments.get("webhook_config_file") {
+            Some(path) if !path.is_empty() => path[0].clone(),
+            _ => {
+                println!("--webhook_config_file is required for webhook_auth authenticator");
+                std::process::exit(1);
+            }
+        };
+        let endpoint = match kubeconfig::load(&config_file, REQUEST_TIMEOUT) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                println!("--webhook_config_file: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if !endpoint.server.starts_with("https://") {
+            println!("WARNING: upstream webhook {} is not using https, tokens are sent in plaintext", endpoint.server);
+        }
+
+        //Get --webhook_group_mapping_file argument, optional
+        let group_mapping = match arguments.get("webhook_group_mapping_file") {
+            Some(path) if !path.is_empty() => {
+                if !Path::new(&path[0]).exists() {
+                    println!("--webhook_group_mapping_file is not valid");
+                    std::process::exit(1);
+                }
+                match FileStore::load(&path[0], WebhookAuthenticator::parse_group_mapping) {
+                    Ok(mapping) => Some(mapping),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        WebhookAuthenticator { endpoint, group_mapping }
+    }
+}
+
+impl WebhookAuthenticator {
+    //Post the TokenReview to the upstream webhook
+    async fn review(&self, review: &TokenRequest) -> Result<TokenReviewResponse, String> {
+        let mut request = self.endpoint.client.post(&self.endpoint.server).json(review);
+        if let Some(token) = &self.endpoint.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("request to {} failed: {}", self.endpoint.server, e))?;
+
+        //Some webhooks, like kubeauth itself, answer denied tokens with 401 and a TokenReview
+        let status = response.status();
+        match response.json::<TokenReviewResponse>().await {
+            Ok(review) => Ok(review),
+            Err(_) if !status.is_success() => Err(format!("{} returned {}", self.endpoint.server, status)),
+            Err(e) => Err(format!("invalid TokenReview from {}: {}", self.endpoint.server, e)),
+        }
+    }
+
+    //Map the upstream groups to kubeauth groups
+    fn map_groups(&self, upstream: Vec<String>) -> Vec<String> {
+        let mapping = match &self.group_mapping {
+            Some(mapping) => mapping.get(),
+            None => return upstream,
+        };
+
+        let mut groups: Vec<String> = vec![];
+        for group in upstream {
+            let mapped = match mapping.get(&group) {
+                Some(mapped) => mapped.clone(),
+                None => vec![group],
+            };
+            for group in mapped {
+                if !groups.contains(&group) {
+                    groups.push(group);
+                }
+            }
+        }
+        groups
+    }
+
+    //Parse the group mapping file, a JSON object of upstream group -> list of groups
+    fn parse_group_mapping(content: &str) -> Result<HashMap<String, Vec<String>>, String> {
+        serde_json::from_str(content).map_err(|e| format!("Invalid group mapping file: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::authenticators::test_server::TestServer;
+
+    //A YAML kubeconfig like the one of the kube-apiserver
+    fn authenticator(server: &str) -> WebhookAuthenticator {
+        let port = server.rsplit(":").next().unwrap();
+        let path = std::env::temp_dir().join(format!("kubeauth-webhook-test-{}.yaml", port));
+        let kubeconfig = format!(
+            "apiVersion: v1\nkind: Config\ncurrent-context: webhook\nclusters:\n- name: upstream\n  cluster:\n    server: {}/authenticate\nusers:\n- name: kubeauth\n  user:\n    token: upstream-secret\ncontexts:\n- name: webhook\n  context:\n    cluster: upstream\n    user: kubeauth\n",
+            server
+        );
+        fs::write(&path, kubeconfig).unwrap();
+        let mut arguments = HashMap::new();
+        arguments.insert(String::from("webhook_config_file"), vec![path.to_string_lossy().to_string()]);
+        WebhookAuthenticator::new(arguments)
+    }
+
+    fn review(authenticated: bool, error: &str) -> String {
+        json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "status": {"authenticated": authenticated, "user": {"username": "alice", "uid": "42", "groups": ["dev"]}, "error": error}
+        })
+        .to_string()
+    }
+
+    #[rocket::async_test]
+    async fn tokens_are_reviewed_by_the_upstream() {
+        let server = TestServer::start(|request| {
+            let token: Value = serde_json::from_str(&request.body).unwrap();
+            match token["spec"]["token"].as_str() {
+                Some("good") => (200, review(true, "")),
+                _ => (401, review(false, "")),
+            }
+        });
+        let webhook = authenticator(&server.url);
+
+        let result = webhook.auth("good", &[]).await;
+        assert!(result.authenticated);
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.uid, "42");
+        assert_eq!(result.groups, vec!["dev"]);
+        assert_eq!(server.requests()[0].headers["authorization"], "Bearer upstream-secret");
+
+        let result = webhook.auth("bad", &[]).await;
+        assert!(!result.authenticated);
+        assert_eq!(result.error, None);
+    }
+
+    #[rocket::async_test]
+    async fn upstream_errors_are_failures() {
+        let server = TestServer::start(|request| {
+            let token: Value = serde_json::from_str(&request.body).unwrap();
+            match token["spec"]["token"].as_str() {
+                Some("error") => (503, review(false, "ldap server unavailable")),
+                _ => (500, String::from("internal error")),
+            }
+        });
+        let webhook = authenticator(&server.url);
+
+        for token in ["error", "broken"] {
+            let result = webhook.auth(token, &[]).await;
+            assert!(!result.authenticated);
+            assert!(result.error.is_some(), "{}", token);
+        }
+
+        //Nothing listens on the port of a dropped listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let result = authenticator(&format!("http://127.0.0.1:{}", port)).auth("good", &[]).await;
+        assert!(result.error.is_some());
+    }
+}
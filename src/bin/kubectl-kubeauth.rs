@@ -0,0 +1,335 @@
+/*
+kubectl-kubeauth is a kubectl exec credential plugin for kubeauth.
+It asks for the credentials, exchanges them at the /login route of kubeauth for a short lived token
+and prints the token as ExecCredential (client.authentication.k8s.io/v1) for kubectl.
+The token is cached in the user's cache dir until it expires, so the credentials are only asked again then.
+With --ssh_key it creates ssh signed tokens for the ssh_sig_auth authenticator instead, without asking anything.
+
+Example kubeconfig user:
+users:
+- name: kubeauth
+  user:
+    exec:
+      apiVersion: client.authentication.k8s.io/v1
+      command: kubectl-kubeauth
+      args: ["--server", "https://kubeauth.example.com:8000"]
+      interactiveMode: IfAvailable
+*/
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use cmdparser::Parser;
+use serde::{Deserialize, Serialize};
+
+const EXEC_CREDENTIAL_API_VERSION: &str = "client.authentication.k8s.io/v1";
+
+//Tokens that expire within this margin are not used from the cache anymore
+const EXPIRY_MARGIN_SECONDS: i64 = 30;
+
+//The sshsig namespace of ssh signed tokens, has to match the ssh_sig_auth authenticator
+const SSH_SIG_NAMESPACE: &str = "kubeauth";
+
+//Seconds kubectl uses a ssh signed token, kubeauth accepts it for 60 seconds by default
+const SSH_TOKEN_LIFETIME_SECONDS: i64 = 30;
+
+//The ExecCredential printed for kubectl
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredential {
+    api_version: String,
+    kind: String,
+    status: ExecCredentialStatus,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration_timestamp: Option<String>,
+}
+
+//The response of the kubeauth /login route, also the content of the cache file
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginResponse {
+    token: String,
+    expiration_timestamp: String,
+}
+
+#[derive(Deserialize)]
+struct LoginError {
+    error: String,
+}
+
+fn print_help() {
+    println!("kubectl-kubeauth is a kubectl exec credential plugin for kubeauth.");
+    println!("Usage: kubectl-kubeauth --server <kubeauth url> [--username <username>] [--ca <ca file>]");
+    println!("       kubectl-kubeauth --ssh_key <private key> --username <username> --audience <audience>");
+    println!("Arguments:");
+    println!("\t--server: The url of kubeauth, e.g. https://kubeauth.example.com:8000");
+    println!("\t--username: The username to log in with, asked for if not set");
+    println!("\t--ca: The ca certificate to verify kubeauth with (optional)");
+    println!("\t--insecure_skip_tls_verify: true to skip verifying the certificate of kubeauth");
+    println!("\t--ssh_key: Create ssh signed tokens with this key (or its public key if the key is in the ssh-agent)");
+    println!("\t--audience: The --ssh_sig_audience of kubeauth, required with --ssh_key");
+    println!("Flags:");
+    println!("\t-h, --help: Prints this help message");
+    println!("\t--logout: Removes the cached token");
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("kubectl-kubeauth: {}", message);
+    std::process::exit(1);
+}
+
+fn main() {
+    let (arguments, flags) = Parser::new().merge_values(true).parse();
+
+    if flags.iter().any(|f| f == "h" || f == "help") {
+        print_help();
+        std::process::exit(0);
+    }
+
+    //ssh signed tokens don't need the server, kubeauth checks them when kubectl uses them
+    if let Some(ssh_key) = arguments.get("ssh_key") {
+        print_exec_credential(ssh_signed_token(&ssh_key[0], &arguments));
+        return;
+    }
+
+    //Get required server argument
+    let server = match arguments.get("server") {
+        Some(server) => server[0].trim_end_matches("/").to_string(),
+        None => fail("--server is required, see --help"),
+    };
+
+    let username = arguments.get("username").map(|u| u[0].clone());
+    let cache_file = cache_file(&server, username.as_deref());
+
+    if flags.iter().any(|f| f == "logout") {
+        let _ = fs::remove_file(&cache_file);
+        std::process::exit(0);
+    }
+
+    //Use the cached token as long as it is valid
+    if let Some(cached) = read_cache(&cache_file) {
+        print_exec_credential(cached);
+        return;
+    }
+
+    //kubectl tells the plugin if it may interact with the user
+    if !is_interactive() {
+        fail("no valid cached token and kubectl does not allow to ask for credentials");
+    }
+
+    let username = match username {
+        Some(username) => username,
+        None => prompt("Username: "),
+    };
+    let password = match rpassword::prompt_password(format!("Password for {}: ", username)) {
+        Ok(password) => password,
+        Err(e) => fail(&format!("failed to read password: {}", e)),
+    };
+
+    let login = request_login_token(&server, &arguments, &username, &password);
+    write_cache(&cache_file, &login);
+    print_exec_credential(login);
+}
+
+//Exchange the credentials for a login token at kubeauth
+fn request_login_token(
+    server: &str,
+    arguments: &HashMap<String, Vec<String>>,
+    username: &str,
+    password: &str,
+) -> LoginResponse {
+    let mut client = reqwest::blocking::Client::builder();
+
+    //Get optional ca argument
+    if let Some(ca) = arguments.get("ca") {
+        let pem = match fs::read(&ca[0]) {
+            Ok(pem) => pem,
+            Err(e) => fail(&format!("failed to read --ca {}: {}", ca[0], e)),
+        };
+        match reqwest::Certificate::from_pem(&pem) {
+            Ok(certificate) => client = client.add_root_certificate(certificate),
+            Err(e) => fail(&format!("--ca {} is not a valid certificate: {}", ca[0], e)),
+        }
+    }
+
+    //Get optional insecure_skip_tls_verify argument
+    if arguments
+        .get("insecure_skip_tls_verify")
+        .is_some_and(|v| v[0] == "true")
+    {
+        client = client.danger_accept_invalid_certs(true);
+    }
+
+    let client = match client.build() {
+        Ok(client) => client,
+        Err(e) => fail(&format!("failed to create http client: {}", e)),
+    };
+
+    let mut body = HashMap::new();
+    body.insert("username", username);
+    body.insert("password", password);
+
+    let response = match client.post(format!("{}/login", server)).json(&body).send() {
+        Ok(response) => response,
+        Err(e) => fail(&format!("failed to reach kubeauth at {}: {}", server, e)),
+    };
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error = response
+            .json::<LoginError>()
+            .map(|e| e.error)
+            .unwrap_or_default();
+        fail(&format!("login failed ({}): {}", status, error));
+    }
+
+    match response.json::<LoginResponse>() {
+        Ok(login) => login,
+        Err(e) => fail(&format!("invalid login response: {}", e)),
+    }
+}
+
+//Create a user:timestamp:signature token, signed by ssh-keygen so keys in the ssh-agent work too
+fn ssh_signed_token(ssh_key: &str, arguments: &HashMap<String, Vec<String>>) -> LoginResponse {
+    let username = match arguments.get("username") {
+        Some(username) => username[0].clone(),
+        None => fail("--username is required with --ssh_key"),
+    };
+    let audience = match arguments.get("audience") {
+        Some(audience) => audience[0].clone(),
+        None => fail("--audience is required with --ssh_key"),
+    };
+
+    let now = Utc::now();
+    let message = format!("{}:{}", now.timestamp(), audience);
+
+    let mut child = match Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-q", "-f", ssh_key, "-n", SSH_SIG_NAMESPACE])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => fail(&format!("failed to run ssh-keygen: {}", e)),
+    };
+    //The message is written and stdin closed, ssh-keygen signs stdin until EOF
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(message.as_bytes()) {
+            fail(&format!("failed to write to ssh-keygen: {}", e));
+        }
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => fail(&format!("ssh-keygen failed with {}", output.status)),
+        Err(e) => fail(&format!("failed to run ssh-keygen: {}", e)),
+    };
+
+    //The token has the base64 of the signature without the armor lines
+    let signature: String = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    LoginResponse {
+        token: format!("{}:{}:{}", username, now.timestamp(), signature),
+        expiration_timestamp: (now + Duration::seconds(SSH_TOKEN_LIFETIME_SECONDS)).to_rfc3339(),
+    }
+}
+
+//The cache file of a server and username in the user's cache dir
+fn cache_file(server: &str, username: Option<&str>) -> PathBuf {
+    let key = format!("{}_{}", server, username.unwrap_or(""));
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("kubeauth")
+        .join(format!("{}.json", name))
+}
+
+//Read the cached token, returns None if there is none or if it is about to expire
+fn read_cache(cache_file: &PathBuf) -> Option<LoginResponse> {
+    let content = fs::read_to_string(cache_file).ok()?;
+    let cached: LoginResponse = serde_json::from_str(&content).ok()?;
+    let expires = DateTime::parse_from_rfc3339(&cached.expiration_timestamp).ok()?;
+
+    if expires.with_timezone(&Utc) - Duration::seconds(EXPIRY_MARGIN_SECONDS) <= Utc::now() {
+        return None;
+    }
+    Some(cached)
+}
+
+//Cache the token, only readable by the user
+fn write_cache(cache_file: &PathBuf, login: &LoginResponse) {
+    let result = (|| -> io::Result<()> {
+        if let Some(dir) = cache_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(cache_file)?;
+        file.write_all(serde_json::to_string(login)?.as_bytes())
+    })();
+
+    //Not caching only means asking for the credentials again next time
+    if let Err(e) = result {
+        eprintln!("kubectl-kubeauth: failed to cache token in {}: {}", cache_file.display(), e);
+    }
+}
+
+fn print_exec_credential(login: LoginResponse) {
+    let credential = ExecCredential {
+        api_version: EXEC_CREDENTIAL_API_VERSION.to_string(),
+        kind: String::from("ExecCredential"),
+        status: ExecCredentialStatus {
+            token: login.token,
+            expiration_timestamp: Some(login.expiration_timestamp),
+        },
+    };
+    println!("{}", serde_json::to_string(&credential).unwrap());
+}
+
+//kubectl passes KUBERNETES_EXEC_INFO, spec.interactive tells if stdin may be used
+fn is_interactive() -> bool {
+    match std::env::var("KUBERNETES_EXEC_INFO") {
+        Ok(info) => serde_json::from_str::<serde_json::Value>(&info)
+            .ok()
+            .and_then(|info| info["spec"]["interactive"].as_bool())
+            .unwrap_or(false),
+        //Not run by kubectl, e.g. for testing on the command line
+        Err(_) => true,
+    }
+}
+
+//Prompt on stderr, stdout is reserved for the ExecCredential
+fn prompt(message: &str) -> String {
+    eprint!("{}", message);
+    let _ = io::stderr().flush();
+
+    let mut line = String::new();
+    if let Err(e) = io::stdin().read_line(&mut line) {
+        fail(&format!("failed to read input: {}", e));
+    }
+    line.trim().to_string()
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+//The login request struct
+//Either username and password or a token (e.g. an api token) are exchanged for a login token
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+//The login response struct
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginResponse {
+    pub token: String,
+    //rfc3339 timestamp, same format as the expirationTimestamp of a kubectl ExecCredential
+    pub expiration_timestamp: String,
+}
+
+//The login error response struct
+#[derive(Serialize)]
+pub struct LoginError {
+    pub error: String,
+}
+
+impl LoginRequest {
+    //The token that is run through the auth pipeline
+    pub fn credentials(&self) -> Option<String> {
+        match (&self.username, &self.password, &self.token) {
+            (Some(username), Some(password), None) => Some(format!("{}:{}", username, password)),
+            (None, None, Some(token)) => Some(token.clone()),
+            _ => None,
+        }
+    }
+}
\ No newline at end of file
@@ -20,6 +20,7 @@ This application is licensed under the MIT license.
 //Import the required libraries
 use core::str;
 use std::collections::HashMap;
+use std::sync::Arc;
 use authenticators::ldap::LdapAuthenticator;
 use rocket::config::CipherSuite;
 use rocket::config::Config as RocketConfig;
@@ -31,14 +32,34 @@ use rocket::serde::json::Json;
 use rocket::State;
 use token_review::default_token_review_response;
 
+use crate::authenticators::authenticator::AuthResult;
 use crate::authenticators::authenticator::Authenticator;
 use crate::authenticators::authenticator::AUTHENTICATORS;
 use crate::authenticators::json::JsonAuthenticator;
+use crate::authenticators::token_file::TokenFileAuthenticator;
+use crate::authenticators::htpasswd::HtpasswdAuthenticator;
+use crate::authenticators::login_token::LoginTokenAuthenticator;
+use crate::login::LoginError;
+use crate::login::LoginRequest;
+use crate::login::LoginResponse;
+use crate::authenticators::jwt::JwtAuthenticator;
+use crate::authenticators::oidc::OidcAuthenticator;
+use crate::authenticators::introspection::IntrospectionAuthenticator;
+use crate::authenticators::webhook::WebhookAuthenticator;
+use crate::authenticators::k8s_sa::K8sSaAuthenticator;
+use crate::authenticators::radius::RadiusAuthenticator;
+use crate::authenticators::totp::{self, TotpChecker, PASSWORD_AUTHENTICATORS};
+use crate::authenticators::ssh_sig::SshSigAuthenticator;
+use crate::authenticators::http::HttpAuthenticator;
+use crate::authenticators::exec::ExecAuthenticator;
+use crate::authenticators::wasm::WasmAuthenticator;
+use crate::authenticators::script::{ScriptAuthenticator, ScriptTransform};
 use crate::token_review::TokenRequest;
 use crate::token_review::TokenReviewResponse;
 use cmdparser::Parser;
 
 pub mod authenticators;
+pub mod login;
 pub mod token_review;
 
 //The index route
@@ -54,46 +75,136 @@ async fn run_auth_pipeline(
     arguments: &HashMap<String, Vec<String>>, //Arguments are or could be used to pass parameters to authenticators
     shared: &State<SharedData>,
     token: &str,
-) -> (bool, String, Vec<String>) {
+    audiences: &[String],
+) -> AuthResult {
+    //The password authenticators are asked with the password with and without the TOTP code
+    let json_auth = shared.json_auth_mod.as_deref();
+    let credentials = shared.totp.split(token, json_auth);
+    let whole_token = [totp::Credentials {
+        token: token.to_string(),
+        code: None,
+    }];
+
+    //Error of a failed backend, only returned if no authenticator grants access
+    let mut error: Option<String> = None;
+
     //Loop through authenticators
     for authenticator in authenticators {
-        //match authenticator
-        match authenticator.as_str() {
-            "json_auth" => {
-                //Create json auther
-                let json_auth = shared.json_auth_mod.lock().await;
-
-                //Run auth
-                let (auth, username, groups) = json_auth.auth(token).await;
-
-                //Check if auth was successful
-                if auth {
-                    //Return true
-                    return (true, username, groups);
-                }
+        let password_authenticator = PASSWORD_AUTHENTICATORS.contains(&authenticator.as_str());
+        let tries = if password_authenticator { &credentials[..] } else { &whole_token[..] };
+
+        for attempt in tries {
+            let result = match run_authenticator(authenticator, shared, &attempt.token, audiences).await {
+                Some(result) => result,
+                //Return false
+                None => return AuthResult::denied(),
+            };
+
+            if result.error.is_some() {
+                error = result.error.clone();
             }
-            "ldap_auth" => {
-                //Create ldap auther
-                let ldap_auth = shared.ldap_auth_mod.lock().await;
 
-                //Run auth
-                let (auth, username, groups) = ldap_auth.auth(token).await;
+            //Check if auth was successful
+            if result.authenticated {
+                //The password was right, but the code of the granted user has to be right too
+                if password_authenticator && !shared.totp.verify(&result.username, attempt.code.as_deref(), json_auth) {
+                    return AuthResult::denied();
+                }
 
-                //Check if auth was successful
-                if auth {
-                    //Return true
-                    return (true, username, groups);
+                //The transform script can change or deny the result of every authenticator
+                if let Some(script_transform) = &shared.script_transform {
+                    return script_transform.transform(authenticator, result);
                 }
-            }
-            _ => {
-                //Return false
-                return (false, String::from(""), vec![]);
+
+                //Return true
+                return result;
             }
         }
     }
 
-    //Return false
-    return (false, String::from(""), vec![]);
+    //Return false, or the error if a backend failed
+    match error {
+        Some(error) => AuthResult::failed(&error),
+        None => AuthResult::denied(),
+    }
+}
+
+//Run one authenticator, None if the authenticator is unknown
+async fn run_authenticator(
+    authenticator: &str,
+    shared: &State<SharedData>,
+    auth_token: &str,
+    audiences: &[String],
+) -> Option<AuthResult> {
+    //match authenticator, only selected authenticators are loaded into shared data
+    let result = match authenticator {
+        "json_auth" => match &shared.json_auth_mod {
+            Some(json_auth) => json_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "ldap_auth" => match &shared.ldap_auth_mod {
+            Some(ldap_auth) => ldap_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "token_file_auth" => match &shared.token_file_auth_mod {
+            Some(token_file_auth) => token_file_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "htpasswd_auth" => match &shared.htpasswd_auth_mod {
+            Some(htpasswd_auth) => htpasswd_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "login_token_auth" => match &shared.login_token_auth_mod {
+            Some(login_token_auth) => login_token_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "jwt_auth" => match &shared.jwt_auth_mod {
+            Some(jwt_auth) => jwt_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "oidc_auth" => match &shared.oidc_auth_mod {
+            Some(oidc_auth) => oidc_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "introspection_auth" => match &shared.introspection_auth_mod {
+            Some(introspection_auth) => introspection_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "webhook_auth" => match &shared.webhook_auth_mod {
+            Some(webhook_auth) => webhook_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "k8s_sa_auth" => match &shared.k8s_sa_auth_mod {
+            Some(k8s_sa_auth) => k8s_sa_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "radius_auth" => match &shared.radius_auth_mod {
+            Some(radius_auth) => radius_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "ssh_sig_auth" => match &shared.ssh_sig_auth_mod {
+            Some(ssh_sig_auth) => ssh_sig_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "http_auth" => match &shared.http_auth_mod {
+            Some(http_auth) => http_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "exec_auth" => match &shared.exec_auth_mod {
+            Some(exec_auth) => exec_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "wasm_auth" => match &shared.wasm_auth_mod {
+            Some(wasm_auth) => wasm_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        "script_auth" => match &shared.script_auth_mod {
+            Some(script_auth) => script_auth.auth(auth_token, audiences).await,
+            None => AuthResult::denied(),
+        },
+        _ => return None,
+    };
+    Some(result)
 }
 
 //The validate token route
@@ -106,40 +217,152 @@ async fn validate_token(
     //Get the token
     let token = &tokenReq.spec.token;
 
-    //Get authenticators from rocket state
-    let authenticators = shared.authenticators.lock().await;
+    //Get authenticators from rocket state, cloned so the lock isn't held while the pipeline runs
+    let authenticators = shared.authenticators.lock().await.clone();
 
     //Get arguments from rocket state
-    let arguments = shared.arguments.lock().await;
+    let arguments = shared.arguments.lock().await.clone();
 
     //Run auth pipeline
-    let (auth, username, groups) = run_auth_pipeline(&authenticators, &arguments, &shared, token).await;
+    let result = run_auth_pipeline(&authenticators, &arguments, &shared, token, &tokenReq.spec.audiences).await;
 
     //Check if auth was successful
-    if auth {
+    if result.authenticated {
         //Return true
         return status::Custom(
             Status::Ok,
-            Json(token_review::token_review_response(&username, groups)),
+            Json(token_review::token_review_response(&result.username, &result.uid, result.groups, result.audiences, result.extra)),
         );
     }
 
-    //Run auth pipeline
+    //The token could not be checked, the apiserver doesn't cache errors like it caches denials
+    if let Some(error) = &result.error {
+        return status::Custom(Status::ServiceUnavailable, Json(token_review::error_token_review_response(error)));
+    }
 
     return status::Custom(Status::Unauthorized, Json(default_token_review_response()));
 }
 
+//The login route
+//This route exchanges credentials for a short lived token signed by kubeauth, the token is verified by login_token_auth
+#[post("/login", data = "<login_req>")]
+async fn issue_login_token(
+    login_req: Json<LoginRequest>,
+    shared: &State<SharedData>,
+) -> Result<Json<LoginResponse>, status::Custom<Json<LoginError>>> {
+    let error = |status: Status, error: &str| {
+        status::Custom(status, Json(LoginError { error: error.to_string() }))
+    };
+
+    //Login is only available if login_token_auth is selected
+    let issuer = match &shared.login_token_auth_mod {
+        Some(issuer) => issuer,
+        None => return Err(error(Status::NotFound, "login is not enabled")),
+    };
+
+    let token = match login_req.credentials() {
+        Some(token) => token,
+        None => return Err(error(Status::BadRequest, "either username and password or token is required")),
+    };
+
+    //Get authenticators from rocket state, login tokens can't be used to get a new login token
+    let authenticators: Vec<String> = shared
+        .authenticators
+        .lock()
+        .await
+        .iter()
+        .filter(|a| a.as_str() != "login_token_auth")
+        .cloned()
+        .collect();
+
+    //Get arguments from rocket state
+    let arguments = shared.arguments.lock().await.clone();
+
+    //Run auth pipeline
+    let result = run_auth_pipeline(&authenticators, &arguments, &shared, &token, &[]).await;
+    if result.error.is_some() {
+        return Err(error(Status::ServiceUnavailable, "credentials can not be checked, try again later"));
+    }
+    if !result.authenticated {
+        return Err(error(Status::Unauthorized, "invalid credentials"));
+    }
+
+    match issuer.issue(&result) {
+        Ok((token, expires)) => {
+            println!("Issued login token for user {}, expires at {}", result.username, expires);
+            Ok(Json(LoginResponse {
+                token,
+                expiration_timestamp: expires.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            }))
+        }
+        Err(e) => {
+            println!("{}", e);
+            Err(error(Status::ServiceUnavailable, "login tokens can not be issued"))
+        }
+    }
+}
+
 struct SharedData {
     authenticators: futures::lock::Mutex<Vec<String>>,
     arguments: futures::lock::Mutex<HashMap<String, Vec<String>>>,
-    json_auth_mod: futures::lock::Mutex<JsonAuthenticator>,
-    ldap_auth_mod: futures::lock::Mutex<LdapAuthenticator>,
+    json_auth_mod: Option<Arc<JsonAuthenticator>>,
+    ldap_auth_mod: Option<Arc<LdapAuthenticator>>,
+    token_file_auth_mod: Option<Arc<TokenFileAuthenticator>>,
+    htpasswd_auth_mod: Option<Arc<HtpasswdAuthenticator>>,
+    login_token_auth_mod: Option<Arc<LoginTokenAuthenticator>>,
+    jwt_auth_mod: Option<Arc<JwtAuthenticator>>,
+    oidc_auth_mod: Option<Arc<OidcAuthenticator>>,
+    introspection_auth_mod: Option<Arc<IntrospectionAuthenticator>>,
+    webhook_auth_mod: Option<Arc<WebhookAuthenticator>>,
+    k8s_sa_auth_mod: Option<Arc<K8sSaAuthenticator>>,
+    radius_auth_mod: Option<Arc<RadiusAuthenticator>>,
+    totp: TotpChecker,
+    ssh_sig_auth_mod: Option<Arc<SshSigAuthenticator>>,
+    http_auth_mod: Option<Arc<HttpAuthenticator>>,
+    exec_auth_mod: Option<Arc<ExecAuthenticator>>,
+    wasm_auth_mod: Option<Arc<WasmAuthenticator>>,
+    script_auth_mod: Option<Arc<ScriptAuthenticator>>,
+    script_transform: Option<ScriptTransform>,
+}
+
+//Create an authenticator only if it has been selected, so unselected authenticators don't require their arguments
+fn load_authenticator<T: Authenticator>(
+    name: &str,
+    authenticators: &Vec<String>,
+    arguments: &HashMap<String, Vec<String>>,
+) -> Option<Arc<T>> {
+    if authenticators.iter().any(|a| a == name) {
+        Some(Arc::new(T::new(arguments.clone())))
+    } else {
+        None
+    }
 }
 
 #[launch]
 fn rocket() -> _ {
     let (arguments, _flags) = Parser::new().merge_values(true).parse();
 
+    //Subcommands run instead of the server
+    let subcommand: Vec<String> = std::env::args()
+        .skip(1)
+        .take_while(|arg| !arg.starts_with("-"))
+        .collect();
+    match subcommand.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[..] {
+        [] => (),
+        ["token", "create"] => {
+            JsonAuthenticator::create_api_token(&arguments);
+            std::process::exit(0);
+        }
+        ["totp", "enroll"] => {
+            totp::enroll(&arguments);
+            std::process::exit(0);
+        }
+        _ => {
+            println!("Unknown subcommand {}, see --help", subcommand.join(" "));
+            std::process::exit(1);
+        }
+    }
+
     //If -h or --help is set, print help and exit
     if arguments.get("h").or(arguments.get("help")).is_some_and(|x| x.len() > 0) || _flags.len() > 0
     {
@@ -147,9 +370,25 @@ fn rocket() -> _ {
         println!("KubeAuth is a simple authentication provider for kubernetes.");
         println!("It is used to validate tokens sent by the kubernetes api server.");
         println!("Usage: kubeauth -a <authenticator> [-p <port>] [-ip <ip>]");
+        println!("       kubeauth token create [--id <id>] [--description <text>] [--expires <rfc3339>] [--groups <group>...]");
+        println!("       kubeauth totp enroll --username <username> [--totp_issuer <issuer>] [--totp_secrets_file <file>]");
         println!("Authenticators:");
         println!("\tjson_auth: Uses a json file to authenticate users");
         println!("\tldap_auth: Uses ldap to authenticate users");
+        println!("\ttoken_file_auth: Uses a kube-apiserver style static token csv file to authenticate users");
+        println!("\thtpasswd_auth: Uses an apache htpasswd file and an optional htgroup file to authenticate users");
+        println!("\tlogin_token_auth: Verifies the short lived tokens issued by the /login route");
+        println!("\tjwt_auth: Verifies JWTs of other services against local JWKS or PEM keys");
+        println!("\toidc_auth: Verifies OIDC id tokens of one or more issuers using their discovery documents");
+        println!("\tintrospection_auth: Checks opaque OAuth2 access tokens with an RFC 7662 introspection endpoint");
+        println!("\twebhook_auth: Forwards the token as TokenReview to an upstream token webhook");
+        println!("\tk8s_sa_auth: Checks ServiceAccount tokens of a remote cluster with its TokenReview API");
+        println!("\tradius_auth: Sends user:password tokens as Access-Request to one or more RADIUS servers");
+        println!("\tssh_sig_auth: Verifies user:timestamp:signature tokens signed with the ssh key of the user");
+        println!("\thttp_auth: Sends a templated request to a http api and maps the JSON response to a user");
+        println!("\texec_auth: Runs a program that gets the token as JSON on stdin and writes the user as JSON to stdout");
+        println!("\twasm_auth: Runs authenticators written as sandboxed WebAssembly modules");
+        println!("\tscript_auth: Runs the authenticate function of a rhai script");
         println!("Flags:");
         println!("\t-h, --help: Prints this help message");
         println!("Arguments:");
@@ -163,13 +402,80 @@ fn rocket() -> _ {
         println!("\tjson_auth:");
         println!("\t\t--json_user_file_path: The users json file to use");
 
-        //TODO: Add ldap arguments, there are not implemented yet
+        //ldap_auth is configured with environment variables
         println!("\tldap_auth:");
-        println!("\t\t--ldap_url: The ldap host url to use");
-        println!("\t\t--ldap_bind_dn: The ldap bind dn to use");
-        println!("\t\t--ldap_bind_password: The ldap bind password to use");
-        println!("\t\t--ldap_base_dn: The ldap base dn to use");
-        println!("\t\t--ldap_filter: The ldap filter to use");
+        println!("\t\tConfigured with the LDAP_* environment variables (LDAP_SERVER_URL, LDAP_BASE_DN, LDAP_FILTER, ...), see src/authenticators/README_ldap.md");
+        println!("\ttoken_file_auth:");
+        println!("\t\t--token_file_path: The token csv file to use (token,user,uid,\"group1,group2\")");
+        println!("\thtpasswd_auth:");
+        println!("\t\t--htpasswd_file_path: The htpasswd file to use");
+        println!("\t\t--htgroup_file_path: The htgroup file to read group memberships from (optional)");
+        println!("\tlogin_token_auth:");
+        println!("\t\t--login_public_keys: The public key pem files to verify tokens with, the file name is the key id");
+        println!("\t\t--login_private_key: The private key pem file to sign tokens with, enables /login (optional)");
+        println!("\t\t--login_algorithm: EdDSA (default), ES256 or RS256");
+        println!("\t\t--login_token_ttl: Lifetime of issued tokens in seconds, defaults to 3600");
+        println!("\tjwt_auth:");
+        println!("\t\t--jwt_keys_file: The JWKS or PEM file with the public keys (RS256, ES256, EdDSA)");
+        println!("\t\t--jwt_issuer: The required iss claim");
+        println!("\t\t--jwt_audiences: The accepted aud claims if the TokenReview has no audiences");
+        println!("\t\t--jwt_leeway: Clock skew tolerance for exp and nbf in seconds, defaults to 60");
+        println!("\t\t--jwt_username_claim: The claim path of the username, defaults to sub");
+        println!("\t\t--jwt_uid_claim: The claim path of the uid, defaults to sub");
+        println!("\t\t--jwt_groups_claim: The claim path of the groups, defaults to groups");
+        println!("\toidc_auth:");
+        println!("\t\t--oidc_config_file: The JSON file with the issuers, their audiences, claims and prefixes");
+        println!("\tintrospection_auth:");
+        println!("\t\t--introspection_url: The introspection endpoint of the authorization server");
+        println!("\t\t--introspection_client_id: The client id used to authenticate at the endpoint");
+        println!("\t\t--introspection_client_secret_file: The file with the client secret");
+        println!("\t\t--introspection_audiences: The accepted aud values if the TokenReview has no audiences");
+        println!("\t\t--introspection_username_field: The response field of the username, defaults to username, falls back to sub");
+        println!("\t\t--introspection_uid_field: The response field of the uid, defaults to sub");
+        println!("\t\t--introspection_groups_field: The response field of the groups, defaults to scope");
+        println!("\t\t--introspection_groups_prefix: Added to every group, optional");
+        println!("\t\t--introspection_cache_ttl: Seconds active tokens are cached, defaults to 30, 0 disables the cache");
+        println!("\t\t--introspection_ca_file: The CA file to verify the endpoint with, optional");
+        println!("\twebhook_auth:");
+        println!("\t\t--webhook_config_file: The JSON kubeconfig of the upstream webhook, the cluster server is the webhook url");
+        println!("\t\t--webhook_group_mapping_file: A JSON file mapping upstream groups to kubeauth groups, optional");
+        println!("\tk8s_sa_auth:");
+        println!("\t\t--k8s_sa_kubeconfig: The JSON kubeconfig of the remote cluster, needs permission to create tokenreviews");
+        println!("\t\t--k8s_sa_namespaces: The namespaces whose ServiceAccounts are allowed");
+        println!("\t\t--k8s_sa_names: The allowed ServiceAccount names, optional, all ServiceAccounts of the namespaces if not set");
+        println!("\t\t--k8s_sa_username_template: The username, defaults to system:remote-serviceaccount:${{namespace}}:${{name}}");
+        println!("\t\t--k8s_sa_groups: Groups added to every ServiceAccount, optional");
+        println!("\t\t--k8s_sa_audiences: The audiences the tokens are reviewed for, defaults to the TokenReview audiences");
+        println!("\tradius_auth:");
+        println!("\t\t--radius_servers: The RADIUS servers as host or host:port, tried in order");
+        println!("\t\t--radius_secret_file: The file with the shared secret");
+        println!("\t\t--radius_auth_method: pap or chap, defaults to pap");
+        println!("\t\t--radius_timeout: Seconds to wait for an answer, defaults to 3");
+        println!("\t\t--radius_retries: Retries per server before the next server is tried, defaults to 1");
+        println!("\t\t--radius_group_attributes: The attributes the groups are taken from, defaults to Filter-Id Class");
+        println!("\t\t--radius_nas_identifier: The NAS-Identifier sent to the servers, defaults to kubeauth");
+        println!("\t\t--radius_require_message_authenticator: Set to false to accept responses without Message-Authenticator");
+        println!("\ttotp (json_auth, ldap_auth, htpasswd_auth, radius_auth, http_auth):");
+        println!("\t\t--totp_secrets_file: A file with username:base32 secret lines, optional, json users can have a totp_secret instead");
+        println!("\t\t--totp_window: Periods of 30 seconds before and after the current one that are accepted, defaults to 1");
+        println!("\t\t--totp_required: Set to true to deny users without TOTP secret in the password authenticators");
+        println!("\tssh_sig_auth:");
+        println!("\t\t--ssh_sig_user_file_path: The users file with the authorized_keys of the users, defaults to --json_user_file_path");
+        println!("\t\t--ssh_sig_audience: The audience the timestamps are signed for, e.g. the name of the cluster");
+        println!("\t\t--ssh_sig_max_age: Seconds a timestamp is accepted, defaults to 60");
+        println!("\thttp_auth:");
+        println!("\t\t--http_config_file: JSON file with the request template, the JSONPath of the response fields and the tls options");
+        println!("\texec_auth:");
+        println!("\t\t--exec_command: The program to run for every token");
+        println!("\t\t--exec_timeout: Seconds the program may run, defaults to 5");
+        println!("\t\t--exec_max_concurrent: Number of programs running at the same time, defaults to 4");
+        println!("\twasm_auth:");
+        println!("\t\t--wasm_config_file: JSON file with the modules, their capabilities, config and limits");
+        println!("\tscript_auth:");
+        println!("\t\t--script_auth_file: The rhai script with fn authenticate(token, audiences)");
+        println!("\t\t--script_max_operations: Operations a script may run per call, defaults to 100000, also used for --script_transform_file");
+        println!("\tscript_transform (all authenticators):");
+        println!("\t\t--script_transform_file: The rhai script with fn transform(user), changes or denies the results of all authenticators");
         
 
         println!("Example:");
@@ -240,16 +546,28 @@ fn rocket() -> _ {
     //Print authenticators
     println!("Authenticators: {:?}", authenticators_vec);
 
-    let test = arguments.clone();
-    let test2 = arguments.clone();
-    //Create shared data
-    //TODO: For example json_auth_mod can be not selected, but it still expects the arguments and will panic if not.
-    //TODO: Maybe add a check if authenticator is selected and if not, don't add it to shared data
+    //Create shared data, only the selected authenticators are loaded
     let shared_data = SharedData {
+        json_auth_mod: load_authenticator("json_auth", &authenticators_vec, &arguments),
+        ldap_auth_mod: load_authenticator("ldap_auth", &authenticators_vec, &arguments),
+        token_file_auth_mod: load_authenticator("token_file_auth", &authenticators_vec, &arguments),
+        htpasswd_auth_mod: load_authenticator("htpasswd_auth", &authenticators_vec, &arguments),
+        login_token_auth_mod: load_authenticator("login_token_auth", &authenticators_vec, &arguments),
+        jwt_auth_mod: load_authenticator("jwt_auth", &authenticators_vec, &arguments),
+        oidc_auth_mod: load_authenticator("oidc_auth", &authenticators_vec, &arguments),
+        introspection_auth_mod: load_authenticator("introspection_auth", &authenticators_vec, &arguments),
+        webhook_auth_mod: load_authenticator("webhook_auth", &authenticators_vec, &arguments),
+        k8s_sa_auth_mod: load_authenticator("k8s_sa_auth", &authenticators_vec, &arguments),
+        radius_auth_mod: load_authenticator("radius_auth", &authenticators_vec, &arguments),
+        totp: TotpChecker::new(&arguments),
+        ssh_sig_auth_mod: load_authenticator("ssh_sig_auth", &authenticators_vec, &arguments),
+        http_auth_mod: load_authenticator("http_auth", &authenticators_vec, &arguments),
+        exec_auth_mod: load_authenticator("exec_auth", &authenticators_vec, &arguments),
+        wasm_auth_mod: load_authenticator("wasm_auth", &authenticators_vec, &arguments),
+        script_auth_mod: load_authenticator("script_auth", &authenticators_vec, &arguments),
+        script_transform: ScriptTransform::new(&arguments),
         authenticators: futures::lock::Mutex::new(authenticators_vec),
         arguments: futures::lock::Mutex::new(arguments),
-        json_auth_mod: futures::lock::Mutex::new(JsonAuthenticator::new(test)),
-        ldap_auth_mod: futures::lock::Mutex::new(LdapAuthenticator::new(test2)),
     };
 
     let mut config = RocketConfig::release_default();
@@ -291,6 +609,6 @@ fn rocket() -> _ {
     //config   
     rocket::custom(config)
         .manage(shared_data)
-        .mount("/", routes![index, validate_token])
+        .mount("/", routes![index, validate_token, issue_login_token])
         //Set port and listen on all interfaces
 }
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+
 use serde::{Serialize, Deserialize};
 
 
-//The token review request struct
-#[derive(Deserialize)]
+//The token review request struct, also sent to upstream webhooks
+#[derive(Serialize, Deserialize)]
 pub struct TokenRequest {
     pub apiVersion: String,
     pub kind: String,
@@ -10,33 +12,50 @@ pub struct TokenRequest {
 }
 
 //The token review request spec struct
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct TokenReviewRequestSpec {
     pub token: String,
+    //The audiences the token is expected to be valid for, sent by the apiserver if --api-audiences is used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audiences: Vec<String>,
 }
 
-//The token review response struct
-#[derive(Serialize)]
+//The token review response struct, also read from upstream webhooks
+#[derive(Serialize, Deserialize)]
 pub struct TokenReviewResponse {
+    #[serde(alias = "apiVersion", default)]
     pub api_version: String,
+    #[serde(default)]
     pub kind: String,
     pub status: TokenReviewResponseStatus,
 }
 
 //The token review response status struct
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TokenReviewResponseStatus {
+    #[serde(default)]
     pub authenticated: bool,
+    #[serde(default)]
     pub user: TokenReviewResponseUser,
+    //The audiences the token was verified for, omitted if the token is not bound to audiences
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audiences: Vec<String>,
+    //Set if the token could not be checked, omitted otherwise
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
 }
 
-//The token review response user struct
-#[derive(Serialize)]
+//The token review response user struct, upstream webhooks may omit any field
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct TokenReviewResponseUser {
     pub username: String,
     pub uid: String,
     pub groups: Vec<String>,
+    //Additional information about the user, omitted if there is none
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, Vec<String>>,
 }
 
 //function to generate a default token review response with access denied
@@ -51,7 +70,10 @@ pub fn default_token_review_response() -> TokenReviewResponse {
                 username: "".to_string(),
                 uid: "".to_string(),
                 groups: vec![],
-            }
+                extra: HashMap::new(),
+            },
+            audiences: vec![],
+            error: "".to_string(),
         }
     };
 
@@ -59,8 +81,15 @@ pub fn default_token_review_response() -> TokenReviewResponse {
     token_review_response
 }
 
+//Function to generate a token review response for a token that could not be checked
+pub fn error_token_review_response(error: &str) -> TokenReviewResponse {
+    let mut token_review_response = default_token_review_response();
+    token_review_response.status.error = error.to_string();
+    token_review_response
+}
+
 //Function to generate a token review response with access granted and groups
-pub fn token_review_response(username: &str, groups: Vec<String>) -> TokenReviewResponse {
+pub fn token_review_response(username: &str, uid: &str, groups: Vec<String>, audiences: Vec<String>, extra: HashMap<String, Vec<String>>) -> TokenReviewResponse {
     //Create the token review response
     let token_review_response = TokenReviewResponse {
         api_version: "authentication.k8s.io/v1".to_string(),
@@ -69,9 +98,12 @@ pub fn token_review_response(username: &str, groups: Vec<String>) -> TokenReview
             authenticated: true,
             user: TokenReviewResponseUser {
                 username: username.to_string(),
-                uid: username.to_string(),
+                uid: uid.to_string(),
                 groups,
-            }
+                extra,
+            },
+            audiences,
+            error: "".to_string(),
         }
     };
 